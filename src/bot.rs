@@ -1,12 +1,225 @@
 use {
-  anyhow::{Context, Result},
-  serde::{Deserialize, Serialize},
-  tracing::{debug, trace},
+  crate::redact,
+  serde::{Deserialize, Serialize, de::DeserializeOwned},
+  std::{error, fmt, sync::Arc, time::Duration},
+  tokio::{
+    sync::{Semaphore, mpsc, oneshot},
+    time::{Instant, interval_at},
+  },
+  tracing::{debug, trace, warn},
 };
 
+const API_BASE_URL: &str = "https://api.telegram.org";
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+/// First backoff delay for a 429 that doesn't say how long to wait, doubled
+/// on each further retry.
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+/// Messages sent or edited a second across all chats, under the Bot API's
+/// limit of about 30.
+pub const DEFAULT_RATE_LIMIT: u32 = 25;
+
+/// How Telegram should parse the formatting of a message's text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+  Markdown,
+  /// Stricter, and reserved characters in literal text must be escaped with
+  /// [`escape_markdown_v2`].
+  MarkdownV2,
+}
+
+impl ParseMode {
+  fn as_str(self) -> &'static str {
+    match self {
+      ParseMode::Markdown => "Markdown",
+      ParseMode::MarkdownV2 => "MarkdownV2",
+    }
+  }
+
+  /// What `text` should read once sent without this parse mode, dropping the
+  /// escapes MarkdownV2 needed so they don't show up as stray backslashes.
+  fn plain(self, text: &str) -> String {
+    match self {
+      ParseMode::Markdown => text.to_string(),
+      ParseMode::MarkdownV2 => unescape_markdown_v2(text),
+    }
+  }
+}
+
+/// Escapes every character MarkdownV2 reserves, so `text` shows up verbatim.
+pub fn escape_markdown_v2(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+/// Undoes [`escape_markdown_v2`], leaving backslashes before anything else.
+fn unescape_markdown_v2(text: &str) -> String {
+  let mut plain = String::with_capacity(text.len());
+  let mut chars = text.chars().peekable();
+  while let Some(c) = chars.next() {
+    match chars.peek() {
+      Some(&next) if c == '\\' && "_*[]()~`>#+-=|{}.!\\".contains(next) => {
+        plain.push(next);
+        chars.next();
+      }
+      _ => plain.push(c),
+    }
+  }
+  plain
+}
+
+/// Why a Bot API request failed. Converts into [`anyhow::Error`], so `?`
+/// works in callers that don't care which it was.
+#[derive(Debug)]
+pub enum BotError {
+  /// Still rate limited once the retries ran out, with the wait in seconds
+  /// Telegram asked for, if it said.
+  RateLimited { retry_after: Option<u64> },
+  /// Telegram refused the request.
+  Api { description: String },
+  /// The request or its response didn't go through.
+  Http(reqwest::Error),
+  /// The response wasn't the JSON Telegram sends.
+  Parse(json::Error),
+}
+
+impl fmt::Display for BotError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BotError::RateLimited { retry_after: Some(secs) } => {
+        write!(f, "Bot API rate limit (429), retry after {}s", secs)
+      }
+      BotError::RateLimited { retry_after: None } => {
+        write!(f, "Bot API rate limit (429)")
+      }
+      BotError::Api { description } => {
+        write!(f, "Telegram API error: {}", description)
+      }
+      BotError::Http(_) => write!(f, "Failed to send HTTP request"),
+      BotError::Parse(_) => write!(f, "Failed to parse response"),
+    }
+  }
+}
+
+impl BotError {
+  /// Whether Telegram refused the formatting of the text rather than the
+  /// request itself.
+  fn is_parse_error(&self) -> bool {
+    match self {
+      BotError::Api { description } => {
+        description.contains("can't parse entities")
+      }
+      _ => false,
+    }
+  }
+}
+
+impl error::Error for BotError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match self {
+      BotError::Http(e) => Some(e),
+      BotError::Parse(e) => Some(e),
+      BotError::RateLimited { .. } | BotError::Api { .. } => None,
+    }
+  }
+}
+
 pub struct BotClient {
   token: String,
+  base_url: String,
   client: reqwest::Client,
+  max_retries: u32,
+  retry_base_delay: Duration,
+  throttle: Option<Throttle>,
+  outbox: Option<Outbox>,
+}
+
+/// Lets through at most `rate` requests a second. Each request takes a
+/// permit, and one is put back every `1 / rate` seconds, keeping at most
+/// `rate` for a burst.
+struct Throttle {
+  permits: Arc<Semaphore>,
+}
+
+/// How often a [`Throttle`] for `rate` puts a permit back. Past a billion a
+/// second that rounds down to zero, which an interval can't tick at, so it
+/// refills every nanosecond instead.
+fn refill_period(rate: u32) -> Duration {
+  (Duration::from_secs(1) / rate.max(1)).max(Duration::from_nanos(1))
+}
+
+impl Throttle {
+  /// Must be called from within a Tokio runtime, which refills the permits.
+  fn new(rate: u32) -> Self {
+    let rate = rate.max(1);
+    let permits = Arc::new(Semaphore::new(rate as usize));
+    let period = refill_period(rate);
+
+    // Stops refilling once the client is gone
+    let refill = Arc::downgrade(&permits);
+    tokio::spawn(async move {
+      let mut ticks = interval_at(Instant::now() + period, period);
+      loop {
+        ticks.tick().await;
+        let Some(permits) = refill.upgrade() else {
+          break;
+        };
+        if permits.available_permits() < rate as usize {
+          permits.add_permits(1);
+        }
+      }
+    });
+
+    Self { permits }
+  }
+
+  async fn wait(&self) {
+    // The semaphore is never closed
+    if let Ok(permit) = self.permits.acquire().await {
+      permit.forget();
+    }
+  }
+}
+
+/// Hands out turns to send or edit, one at a time and first come, first
+/// served, so a message never overtakes one made before it. A single task
+/// grants each queued turn and waits for it to end before the next.
+struct Outbox {
+  queue: mpsc::UnboundedSender<oneshot::Sender<Turn>>,
+}
+
+/// Holding this is the turn; dropping it lets the next one go.
+type Turn = oneshot::Sender<()>;
+
+impl Outbox {
+  /// Must be called from within a Tokio runtime, which runs the queue.
+  fn new() -> Self {
+    let (queue, mut waiting) = mpsc::unbounded_channel::<oneshot::Sender<_>>();
+
+    // Stops once the client is gone
+    tokio::spawn(async move {
+      while let Some(next) = waiting.recv().await {
+        let (turn, ended) = oneshot::channel();
+        // Skips requests that were dropped while waiting
+        if next.send(turn).is_ok() {
+          let _ = ended.await;
+        }
+      }
+    });
+
+    Self { queue }
+  }
+
+  async fn turn(&self) -> Option<Turn> {
+    let (next, granted) = oneshot::channel();
+    self.queue.send(next).ok()?;
+    granted.await.ok()
+  }
 }
 
 #[derive(Debug, Serialize)]
@@ -30,12 +243,40 @@ struct InlineKeyboardButton {
   callback_data: String,
 }
 
+impl InlineKeyboardMarkup {
+  /// Builds a keyboard from rows of `(label, callback_data)` pairs.
+  fn new(buttons: Vec<Vec<(String, String)>>) -> Self {
+    let inline_keyboard = buttons
+      .into_iter()
+      .map(|row| {
+        row
+          .into_iter()
+          .map(|(text, callback_data)| InlineKeyboardButton {
+            text,
+            callback_data,
+          })
+          .collect()
+      })
+      .collect();
+
+    Self { inline_keyboard }
+  }
+}
+
 #[derive(Debug, Deserialize)]
 struct TelegramResponse<T> {
   ok: bool,
   #[serde(default)]
   description: Option<String>,
   result: Option<T>,
+  #[serde(default)]
+  parameters: Option<ResponseParameters>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseParameters {
+  #[serde(default)]
+  retry_after: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -57,6 +298,15 @@ struct EditMessageTextRequest {
   text: String,
   #[serde(skip_serializing_if = "Option::is_none")]
   parse_mode: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Debug, Serialize)]
+struct EditMessageReplyMarkupRequest {
+  chat_id: i64,
+  message_id: i64,
+  reply_markup: InlineKeyboardMarkup,
 }
 
 #[derive(Debug, Serialize)]
@@ -78,7 +328,6 @@ pub struct Update {
 #[derive(Debug, Deserialize)]
 pub struct CallbackQuery {
   pub id: String,
-  #[allow(dead_code)]
   pub from: User,
   pub message: Option<CallbackMessage>,
   pub data: Option<String>,
@@ -86,7 +335,6 @@ pub struct CallbackQuery {
 
 #[derive(Debug, Deserialize)]
 pub struct User {
-  #[allow(dead_code)]
   pub id: i64,
 }
 
@@ -104,6 +352,16 @@ pub struct BotMessage {
   #[serde(default)]
   pub text: Option<String>,
   pub from: User,
+  #[serde(default)]
+  pub forward_origin: Option<json::Value>,
+  #[serde(default)]
+  pub reply_to_message: Option<RepliedMessage>,
+}
+
+/// The message a [`BotMessage`] replies to, as far as we need it.
+#[derive(Debug, Deserialize)]
+pub struct RepliedMessage {
+  pub message_id: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -114,11 +372,140 @@ struct GetUpdatesRequest {
 
 impl BotClient {
   pub fn new(token: String) -> Self {
-    Self { token, client: reqwest::Client::new() }
+    Self::with_base_url(token, API_BASE_URL.to_string())
+  }
+
+  /// Talks to a Bot API server other than the official one.
+  pub fn with_base_url(token: String, base_url: String) -> Self {
+    Self {
+      token,
+      base_url,
+      client: reqwest::Client::new(),
+      max_retries: DEFAULT_MAX_RETRIES,
+      retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
+      throttle: None,
+      outbox: None,
+    }
+  }
+
+  /// Sends requests through `client`, e.g. the shared proxied one.
+  pub fn with_http_client(mut self, client: reqwest::Client) -> Self {
+    self.client = client;
+    self
+  }
+
+  /// Retries requests rate limited with a 429 up to `max_retries` times.
+  pub fn with_retry(mut self, max_retries: u32, base_delay: Duration) -> Self {
+    self.max_retries = max_retries;
+    self.retry_base_delay = base_delay;
+    self
+  }
+
+  /// Sends and edits at most `per_second` messages a second across all chats,
+  /// waiting for a turn when they come faster; 0 doesn't limit them. Must be
+  /// called from within a Tokio runtime.
+  pub fn with_rate_limit(mut self, per_second: u32) -> Self {
+    self.throttle = (per_second > 0).then(|| Throttle::new(per_second));
+    self
+  }
+
+  /// Sends and edits messages one after another in the order they were
+  /// asked for, instead of racing each other to the chat. Must be called from
+  /// within a Tokio runtime.
+  pub fn with_ordered_sends(mut self) -> Self {
+    self.outbox = Some(Outbox::new());
+    self
+  }
+
+  /// Waits for the sends and edits asked for earlier to finish, holding up
+  /// later ones until the returned turn is dropped.
+  async fn turn(&self) -> Option<Turn> {
+    self.outbox.as_ref()?.turn().await
+  }
+
+  /// Waits until the rate limit lets another message through.
+  async fn throttle(&self) {
+    if let Some(throttle) = &self.throttle {
+      throttle.wait().await;
+    }
+  }
+
+  /// The bot's own user id, which is the prefix of its token.
+  pub fn bot_id(&self) -> Option<i64> {
+    self.token.split_once(':')?.0.parse().ok()
   }
 
   fn api_url(&self, method: &str) -> String {
-    format!("https://api.telegram.org/bot{}/{}", self.token, method)
+    format!("{}/bot{}/{}", self.base_url, self.token, method)
+  }
+
+  /// Posts `request` to `method`, sleeping out 429 responses for as long as
+  /// Telegram's `retry_after` asks, or with exponential backoff if it doesn't
+  /// say, until the retries run out.
+  async fn post<R: Serialize>(
+    &self,
+    method: &str,
+    request: &R,
+  ) -> Result<reqwest::Response, BotError> {
+    let mut attempt = 0;
+    loop {
+      let http_response = self
+        .client
+        .post(self.api_url(method))
+        .json(request)
+        .send()
+        .await
+        .map_err(BotError::Http)?;
+
+      if http_response.status().as_u16() != 429 {
+        return Ok(http_response);
+      }
+
+      let error_text = http_response.text().await.unwrap_or_default();
+      let retry_after = retry_after(&error_text);
+      if attempt >= self.max_retries {
+        debug!("Bot API rate limit (429) on {}: {}", method, error_text);
+        return Err(BotError::RateLimited { retry_after });
+      }
+
+      let delay = retry_after
+        .map(Duration::from_secs)
+        .unwrap_or(self.retry_base_delay * 2u32.saturating_pow(attempt));
+      attempt += 1;
+      debug!(
+        "Bot API rate limit (429) on {}, retry {} of {} in {:?}",
+        method, attempt, self.max_retries, delay
+      );
+      tokio::time::sleep(delay).await;
+    }
+  }
+
+  /// Posts `request` to `method` and reads the result out of the response.
+  async fn call<R: Serialize, T: DeserializeOwned>(
+    &self,
+    method: &str,
+    request: &R,
+  ) -> Result<Option<T>, BotError> {
+    read_result(self.post(method, request).await?).await
+  }
+
+  /// Like [`call`](Self::call), but if Telegram can't parse the formatting of
+  /// the text, sends it once more with `plain` applied so it still arrives.
+  async fn call_formatted<R: Serialize, T: DeserializeOwned>(
+    &self,
+    method: &str,
+    mut request: R,
+    plain: impl FnOnce(&mut R),
+  ) -> Result<Option<T>, BotError> {
+    match self.call(method, &request).await {
+      Err(e) if e.is_parse_error() => {
+        warn!("{} on {}, sending as plain text", e, method);
+        plain(&mut request);
+        self.throttle().await;
+        self.call(method, &request).await
+      }
+      result => result,
+    }
   }
 
   pub async fn send_message_with_buttons(
@@ -126,116 +513,116 @@ impl BotClient {
     chat_id: i64,
     text: String,
     buttons: Vec<Vec<(String, String)>>,
-  ) -> Result<i64> {
-    let inline_keyboard = buttons
-      .into_iter()
-      .map(|row| {
-        row
-          .into_iter()
-          .map(|(text, callback_data)| InlineKeyboardButton {
-            text,
-            callback_data,
-          })
-          .collect()
-      })
-      .collect();
-
+    parse_mode: ParseMode,
+  ) -> Result<i64, BotError> {
     let request = SendMessageRequest {
       chat_id,
       text,
-      parse_mode: Some("Markdown".to_string()),
-      reply_markup: Some(InlineKeyboardMarkup { inline_keyboard }),
+      parse_mode: Some(parse_mode.as_str().to_string()),
+      reply_markup: Some(InlineKeyboardMarkup::new(buttons)),
     };
 
-    trace!("Sending message with buttons to chat {}", chat_id);
+    trace!("Sending message with buttons to chat {}", redact::peer(chat_id));
 
-    let http_response = self
-      .client
-      .post(self.api_url("sendMessage"))
-      .json(&request)
-      .send()
-      .await
-      .context("Failed to send HTTP request")?;
-
-    let status = http_response.status();
-
-    // Handle rate limiting
-    if status.as_u16() == 429 {
-      let error_text = http_response.text().await.unwrap_or_default();
-      debug!("Bot API rate limit (429) reached: {}", error_text);
-      anyhow::bail!("Bot API rate limit (429): {}", error_text);
-    }
-
-    let response_text =
-      http_response.text().await.context("Failed to read response body")?;
+    let _turn = self.turn().await;
+    self.throttle().await;
 
-    trace!("Bot API response: {}", response_text);
-
-    let response: TelegramResponse<Message> = json::from_str(&response_text)
-      .context(format!("Failed to parse response: {}", response_text))?;
-
-    if !response.ok {
-      let error_desc =
-        response.description.unwrap_or_else(|| "Unknown error".to_string());
-      debug!("Telegram API error: {}", error_desc);
-      anyhow::bail!("Telegram API error: {}", error_desc);
-    }
-
-    let message = response.result.context("Missing result in response")?;
+    let message: Message = self
+      .call_formatted("sendMessage", request, |request| {
+        request.parse_mode = None;
+        request.text = parse_mode.plain(&request.text);
+      })
+      .await?
+      .ok_or_else(|| BotError::Api {
+        description: "Missing result in response".to_string(),
+      })?;
 
-    debug!("Sent message {} to chat {}", message.message_id, chat_id);
+    debug!(
+      "Sent message {} to chat {}",
+      message.message_id,
+      redact::peer(chat_id)
+    );
 
     Ok(message.message_id)
   }
 
+  /// Replaces the message text, dropping any inline buttons it had.
   pub async fn edit_message_text(
     &self,
     chat_id: i64,
     message_id: i64,
     text: String,
-  ) -> Result<()> {
+    parse_mode: ParseMode,
+  ) -> Result<(), BotError> {
+    self.edit_message(chat_id, message_id, text, None, parse_mode).await
+  }
+
+  pub async fn edit_message_with_buttons(
+    &self,
+    chat_id: i64,
+    message_id: i64,
+    text: String,
+    buttons: Vec<Vec<(String, String)>>,
+    parse_mode: ParseMode,
+  ) -> Result<(), BotError> {
+    let reply_markup = Some(InlineKeyboardMarkup::new(buttons));
+    self.edit_message(chat_id, message_id, text, reply_markup, parse_mode).await
+  }
+
+  async fn edit_message(
+    &self,
+    chat_id: i64,
+    message_id: i64,
+    text: String,
+    reply_markup: Option<InlineKeyboardMarkup>,
+    parse_mode: ParseMode,
+  ) -> Result<(), BotError> {
     let request = EditMessageTextRequest {
       chat_id,
       message_id,
       text,
-      parse_mode: Some("Markdown".to_string()),
+      parse_mode: Some(parse_mode.as_str().to_string()),
+      reply_markup,
     };
 
-    trace!("Editing message {} in chat {}", message_id, chat_id);
+    trace!("Editing message {} in chat {}", message_id, redact::peer(chat_id));
 
-    let http_response = self
-      .client
-      .post(self.api_url("editMessageText"))
-      .json(&request)
-      .send()
-      .await
-      .context("Failed to send HTTP request")?;
+    let _turn = self.turn().await;
+    self.throttle().await;
 
-    let status = http_response.status();
+    self
+      .call_formatted::<_, Message>("editMessageText", request, |request| {
+        request.parse_mode = None;
+        request.text = parse_mode.plain(&request.text);
+      })
+      .await?;
 
-    // Handle rate limiting
-    if status.as_u16() == 429 {
-      let error_text = http_response.text().await.unwrap_or_default();
-      debug!("Bot API rate limit (429) reached: {}", error_text);
-      anyhow::bail!("Bot API rate limit (429): {}", error_text);
-    }
+    debug!("Edited message {} in chat {}", message_id, redact::peer(chat_id));
 
-    let response_text =
-      http_response.text().await.context("Failed to read response body")?;
+    Ok(())
+  }
+
+  /// Swaps a message's inline buttons, leaving its text as is.
+  pub async fn edit_message_buttons(
+    &self,
+    chat_id: i64,
+    message_id: i64,
+    buttons: Vec<Vec<(String, String)>>,
+  ) -> Result<(), BotError> {
+    let request = EditMessageReplyMarkupRequest {
+      chat_id,
+      message_id,
+      reply_markup: InlineKeyboardMarkup::new(buttons),
+    };
 
-    trace!("Bot API response: {}", response_text);
+    trace!("Editing buttons of message {}", message_id);
 
-    let response: TelegramResponse<Message> = json::from_str(&response_text)
-      .context(format!("Failed to parse response: {}", response_text))?;
+    let _turn = self.turn().await;
+    self.throttle().await;
 
-    if !response.ok {
-      let error_desc =
-        response.description.unwrap_or_else(|| "Unknown error".to_string());
-      debug!("Telegram API error: {}", error_desc);
-      anyhow::bail!("Telegram API error: {}", error_desc);
-    }
+    self.call::<_, Message>("editMessageReplyMarkup", &request).await?;
 
-    debug!("Edited message {} in chat {}", message_id, chat_id);
+    debug!("Edited buttons of message {}", message_id);
 
     Ok(())
   }
@@ -244,7 +631,7 @@ impl BotClient {
     &self,
     callback_query_id: &str,
     text: Option<String>,
-  ) -> Result<()> {
+  ) -> Result<(), BotError> {
     let request = AnswerCallbackQueryRequest {
       callback_query_id: callback_query_id.to_string(),
       text,
@@ -252,30 +639,41 @@ impl BotClient {
 
     trace!("Answering callback query {}", callback_query_id);
 
-    let response = self
-      .client
-      .post(self.api_url("answerCallbackQuery"))
-      .json(&request)
-      .send()
-      .await
-      .context("Failed to send HTTP request")?;
-
-    let response: TelegramResponse<bool> =
-      response.json().await.context("Failed to parse response")?;
-
-    if !response.ok {
-      anyhow::bail!(
-        "Telegram API error: {}",
-        response.description.unwrap_or_else(|| "Unknown error".to_string())
-      );
-    }
+    self.call::<_, bool>("answerCallbackQuery", &request).await?;
 
     debug!("Answered callback query {}", callback_query_id);
 
     Ok(())
   }
 
-  pub async fn get_updates(&self, offset: Option<i64>) -> Result<Vec<Update>> {
+  /// Tells the owner a card's draft is gone and turns the card into an
+  /// expired notice, so its buttons no longer look actionable.
+  pub async fn expire_card(
+    &self,
+    callback_query_id: &str,
+    chat_id: i64,
+    message_id: i64,
+  ) -> Result<(), BotError> {
+    self
+      .answer_callback_query(
+        callback_query_id,
+        Some("This draft has expired".to_string()),
+      )
+      .await?;
+    self
+      .edit_message_text(
+        chat_id,
+        message_id,
+        "⌛ *Expired*".to_string(),
+        ParseMode::Markdown,
+      )
+      .await
+  }
+
+  pub async fn get_updates(
+    &self,
+    offset: Option<i64>,
+  ) -> Result<Vec<Update>, BotError> {
     let request = GetUpdatesRequest { offset, timeout: 30 };
 
     trace!("Getting updates with offset {:?}", offset);
@@ -286,22 +684,290 @@ impl BotClient {
       .json(&request)
       .send()
       .await
-      .context("Failed to send HTTP request")?;
+      .map_err(BotError::Http)?;
 
-    let response: TelegramResponse<Vec<Update>> =
-      response.json().await.context("Failed to parse response")?;
+    let updates: Vec<Update> = read_result(response).await?.unwrap_or_default();
 
-    if !response.ok {
-      anyhow::bail!(
-        "Telegram API error: {}",
-        response.description.unwrap_or_else(|| "Unknown error".to_string())
-      );
+    debug!("Received {} updates", updates.len());
+
+    Ok(updates)
+  }
+}
+
+/// The result of a Bot API response, an error if Telegram says it failed.
+async fn read_result<T: DeserializeOwned>(
+  http_response: reqwest::Response,
+) -> Result<Option<T>, BotError> {
+  let response_text = http_response.text().await.map_err(BotError::Http)?;
+
+  trace!("Bot API response: {}", response_text);
+
+  let response: TelegramResponse<T> =
+    json::from_str(&response_text).map_err(BotError::Parse)?;
+
+  if !response.ok {
+    if let Some(retry_after) = response.parameters.and_then(|p| p.retry_after) {
+      return Err(BotError::RateLimited { retry_after: Some(retry_after) });
     }
+    let description =
+      response.description.unwrap_or_else(|| "Unknown error".to_string());
+    debug!("Telegram API error: {}", description);
+    return Err(BotError::Api { description });
+  }
 
-    let updates = response.result.unwrap_or_default();
+  Ok(response.result)
+}
 
-    debug!("Received {} updates", updates.len());
+/// The wait in seconds a 429 response body asks for.
+fn retry_after(body: &str) -> Option<u64> {
+  let response: TelegramResponse<json::Value> = json::from_str(body).ok()?;
+  response.parameters?.retry_after
+}
 
-    Ok(updates)
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::testing::{MockServer, Response},
+  };
+
+  const RATE_LIMITED: &str = r#"{"ok":false,"error_code":429,
+    "description":"Too Many Requests: retry after 0",
+    "parameters":{"retry_after":0}}"#;
+
+  #[tokio::test]
+  async fn test_rate_limited_send_is_retried() {
+    let server = MockServer::start(vec![
+      Response::json(429, RATE_LIMITED),
+      Response::json(
+        200,
+        r#"{"ok":true,"result":{"message_id":42,"chat":{"id":1}}}"#,
+      ),
+    ])
+    .await;
+    let bot = BotClient::with_base_url("token".to_string(), server.url(""));
+
+    let message_id = bot
+      .send_message_with_buttons(
+        1,
+        "hi".to_string(),
+        vec![],
+        ParseMode::Markdown,
+      )
+      .await
+      .unwrap();
+    assert_eq!(message_id, 42);
+    assert_eq!(server.requests().len(), 2);
+
+    // Once the retries run out the 429 is returned
+    let server =
+      MockServer::start(vec![Response::json(429, RATE_LIMITED)]).await;
+    let bot = BotClient::with_base_url("token".to_string(), server.url(""))
+      .with_retry(1, Duration::ZERO);
+    let err = bot.answer_callback_query("query", None).await.unwrap_err();
+    assert!(matches!(err, BotError::RateLimited { retry_after: Some(0) }));
+    assert_eq!(server.requests().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_rate_limit_spaces_out_sends() {
+    let server = MockServer::start(vec![Response::json(
+      200,
+      r#"{"ok":true,"result":{"message_id":42,"chat":{"id":1}}}"#,
+    )])
+    .await;
+    let bot = Arc::new(
+      BotClient::with_base_url("token".to_string(), server.url(""))
+        .with_rate_limit(20),
+    );
+
+    // A burst of 20 goes out at once, the other 10 one every 50ms
+    let start = Instant::now();
+    let sends: Vec<_> = (0..30)
+      .map(|_| {
+        let bot = bot.clone();
+        tokio::spawn(async move {
+          let text = "hi".to_string();
+          bot
+            .send_message_with_buttons(1, text, vec![], ParseMode::Markdown)
+            .await
+        })
+      })
+      .collect();
+    for send in sends {
+      send.await.unwrap().unwrap();
+    }
+    assert!(
+      start.elapsed() >= Duration::from_millis(500),
+      "{:?}",
+      start.elapsed()
+    );
+    assert_eq!(server.requests().len(), 30);
+  }
+
+  #[test]
+  fn test_huge_rate_limit_still_refills() {
+    assert_eq!(refill_period(20), Duration::from_millis(50));
+    assert_eq!(refill_period(u32::MAX), Duration::from_nanos(1));
+  }
+
+  #[tokio::test]
+  async fn test_ordered_sends_go_out_first_in_first_out() {
+    let sent = r#"{"ok":true,"result":{"message_id":42,"chat":{"id":1}}}"#;
+    // The first send is slow enough for the others to overtake it
+    let server = MockServer::start(vec![
+      Response::json(200, sent).delayed(Duration::from_millis(100)),
+      Response::json(200, sent),
+    ])
+    .await;
+    let bot = BotClient::with_base_url("token".to_string(), server.url(""))
+      .with_ordered_sends();
+
+    let finished = std::sync::Mutex::new(Vec::new());
+    let send = |text: &'static str| {
+      let (bot, finished) = (&bot, &finished);
+      async move {
+        bot
+          .send_message_with_buttons(
+            1,
+            text.into(),
+            vec![],
+            ParseMode::Markdown,
+          )
+          .await
+          .unwrap();
+        finished.lock().unwrap().push(text);
+      }
+    };
+    let edit = async {
+      bot
+        .edit_message_text(1, 42, "third".into(), ParseMode::Markdown)
+        .await
+        .unwrap();
+      finished.lock().unwrap().push("third");
+    };
+    tokio::join!(send("first"), send("second"), edit);
+
+    assert_eq!(*finished.lock().unwrap(), ["first", "second", "third"]);
+    let texts: Vec<_> = server
+      .requests()
+      .iter()
+      .map(|request| request.json()["text"].clone())
+      .collect();
+    assert_eq!(texts, ["first", "second", "third"]);
+  }
+
+  #[tokio::test]
+  async fn test_errors_say_what_failed() {
+    let server = MockServer::start(vec![
+      Response::json(
+        400,
+        r#"{"ok":false,"description":"Bad Request: message is not modified"}"#,
+      ),
+      Response::json(200, "<html>Bad Gateway</html>"),
+    ])
+    .await;
+    let bot = BotClient::with_base_url("token".to_string(), server.url(""));
+
+    let err = bot.edit_message_buttons(1, 42, vec![]).await.unwrap_err();
+    assert!(matches!(
+      &err,
+      BotError::Api { description }
+        if description == "Bad Request: message is not modified"
+    ));
+    let err = bot.get_updates(None).await.unwrap_err();
+    assert!(matches!(err, BotError::Parse(_)));
+
+    // Nothing listens on a port that was just freed
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let bot = BotClient::with_base_url("token".to_string(), url);
+    let err = bot.answer_callback_query("query", None).await.unwrap_err();
+    assert!(matches!(err, BotError::Http(_)));
+  }
+
+  #[tokio::test]
+  async fn test_unparsable_markdown_is_sent_as_plain_text() {
+    const PARSE_ERROR: &str = concat!(
+      r#"{"ok":false,"error_code":400,"description":"Bad Request: "#,
+      r#"can't parse entities: Can't find end of the entity"}"#
+    );
+    const SENT: &str =
+      r#"{"ok":true,"result":{"message_id":42,"chat":{"id":1}}}"#;
+    let server = MockServer::start(vec![
+      Response::json(400, PARSE_ERROR),
+      Response::json(200, SENT),
+      Response::json(400, PARSE_ERROR),
+      Response::json(200, SENT),
+    ])
+    .await;
+    let bot = BotClient::with_base_url("token".to_string(), server.url(""));
+
+    let text = "hi *there".to_string();
+    let message_id = bot
+      .send_message_with_buttons(1, text.clone(), vec![], ParseMode::Markdown)
+      .await
+      .unwrap();
+    assert_eq!(message_id, 42);
+    let escaped = escape_markdown_v2(&text);
+    bot.edit_message_text(1, 42, escaped, ParseMode::MarkdownV2).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    assert_eq!(requests[0].json()["parse_mode"], "Markdown");
+    assert_eq!(requests[2].json()["parse_mode"], "MarkdownV2");
+    for retry in [&requests[1], &requests[3]] {
+      assert!(retry.json().get("parse_mode").is_none());
+      assert_eq!(retry.json()["text"], "hi *there");
+    }
+  }
+
+  #[test]
+  fn test_escape_markdown_v2() {
+    let reply =
+      "Sure_thing *maybe* [see](this) ~ok~ `x` > #1 + a-b = c|d {e}. Hi!";
+    let escaped = escape_markdown_v2(reply);
+    assert_eq!(
+      escaped,
+      "Sure\\_thing \\*maybe\\* \\[see\\]\\(this\\) \\~ok\\~ \\`x\\` \\> \
+       \\#1 \\+ a\\-b \\= c\\|d \\{e\\}\\. Hi\\!"
+    );
+
+    // Every reserved character is escaped, so Telegram reads none as markup
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+      assert!(!"_*[]()~`>#+-=|{}.!".contains(c), "unescaped {:?}", c);
+      if c == '\\' {
+        chars.next();
+      }
+    }
+    assert_eq!(unescape_markdown_v2(&escaped), reply);
+    assert_eq!(unescape_markdown_v2("C:\\dir \\\\ \\q"), "C:\\dir \\ \\q");
+  }
+
+  #[tokio::test]
+  async fn test_expire_card_toasts_and_edits() {
+    let server = MockServer::start(vec![
+      Response::json(200, r#"{"ok":true,"result":true}"#),
+      Response::json(
+        200,
+        r#"{"ok":true,"result":{"message_id":42,"chat":{"id":1}}}"#,
+      ),
+    ])
+    .await;
+    let bot = BotClient::with_base_url("token".to_string(), server.url(""));
+
+    bot.expire_card("query", 1, 42).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let toast = requests[0].json();
+    assert_eq!(toast["callback_query_id"], "query");
+    assert_eq!(toast["text"], "This draft has expired");
+    let edit = requests[1].json();
+    assert_eq!(edit["message_id"], 42);
+    assert_eq!(edit["text"], "⌛ *Expired*");
+    assert!(edit.get("reply_markup").is_none());
   }
 }
@@ -0,0 +1,133 @@
+//! Tokens spent today and this month, counted against the configured budgets
+//! and saved so a restart doesn't reset them. Periods are UTC days and
+//! calendar months.
+
+use serde::{Deserialize, Serialize};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+  Day,
+  Month,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+  /// Days since the Unix epoch that `daily` counts.
+  pub day: u64,
+  pub daily: u64,
+  /// Months since January 1970 that `monthly` counts.
+  pub month: u64,
+  pub monthly: u64,
+}
+
+impl Usage {
+  /// Starts over the counters whose period is over as of `now`.
+  pub fn roll(&mut self, now: u64) {
+    let day = now / DAY_SECS;
+    if day != self.day {
+      self.day = day;
+      self.daily = 0;
+    }
+    let month = month_of(day);
+    if month != self.month {
+      self.month = month;
+      self.monthly = 0;
+    }
+  }
+
+  pub fn add(&mut self, tokens: u64, now: u64) {
+    self.roll(now);
+    self.daily += tokens;
+    self.monthly += tokens;
+  }
+
+  /// The period whose budget is used up as of `now`, if any.
+  pub fn exceeded(
+    &mut self,
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    now: u64,
+  ) -> Option<Period> {
+    self.roll(now);
+    if monthly.is_some_and(|budget| self.monthly >= budget) {
+      Some(Period::Month)
+    } else if daily.is_some_and(|budget| self.daily >= budget) {
+      Some(Period::Day)
+    } else {
+      None
+    }
+  }
+}
+
+/// The UTC date `period` starts over on after `now`, as `YYYY-MM-DD`.
+pub fn reset_date(period: Period, now: u64) -> String {
+  let day = now / DAY_SECS;
+  let (year, month, _) = civil_from_days(day);
+  let (year, month, day) = match period {
+    Period::Day => civil_from_days(day + 1),
+    Period::Month if month == 12 => (year + 1, 1, 1),
+    Period::Month => (year, month + 1, 1),
+  };
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn month_of(day: u64) -> u64 {
+  let (year, month, _) = civil_from_days(day);
+  (year - 1970) * 12 + (month - 1)
+}
+
+/// The (year, month, day) of a day since the Unix epoch, see
+/// https://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+  let z = days + 719_468;
+  let era = z / 146_097;
+  let doe = z % 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + u64::from(month <= 2);
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 2026-10-14 12:00:00 UTC
+  const NOW: u64 = 1_791_979_200;
+
+  #[test]
+  fn test_periods_start_over() {
+    let mut usage = Usage::default();
+    usage.add(100, NOW);
+    assert_eq!(usage.exceeded(Some(100), None, NOW), Some(Period::Day));
+    assert_eq!(usage.exceeded(None, Some(1000), NOW), None);
+
+    usage.add(900, NOW + DAY_SECS);
+    assert_eq!(usage.daily, 900);
+    assert_eq!(usage.monthly, 1000);
+    assert_eq!(
+      usage.exceeded(Some(5000), Some(1000), NOW),
+      Some(Period::Month)
+    );
+
+    // November
+    let next_month = NOW + 18 * DAY_SECS;
+    assert_eq!(usage.exceeded(Some(100), Some(1000), next_month), None);
+    assert_eq!(usage.monthly, 0);
+  }
+
+  #[test]
+  fn test_reset_dates() {
+    assert_eq!(reset_date(Period::Day, NOW), "2026-10-15");
+    assert_eq!(reset_date(Period::Month, NOW), "2026-11-01");
+    assert_eq!(reset_date(Period::Day, 0), "1970-01-02");
+    // 2026-12-31
+    assert_eq!(reset_date(Period::Month, NOW + 78 * DAY_SECS), "2027-01-01");
+    assert_eq!(reset_date(Period::Day, NOW + 78 * DAY_SECS), "2027-01-01");
+  }
+}
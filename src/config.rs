@@ -1,16 +1,37 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+  collections::{HashMap, HashSet},
+  fs,
+  path::Path,
+};
 
 use {
+  crate::llm::{AuthMode, Provider},
   anyhow::{Context, Result},
   config::Config as ConfigBuilder,
   grammers_session::defs::PeerId,
+  regex_automata::meta::Regex,
   serde::{Deserialize, Serialize},
+  tracing::warn,
 };
 
 // Constants
 pub const DEFAULT_SESSION_FILE: &str = "userbot.session";
 pub const DEFAULT_DEBOUNCE_SECONDS: u64 = 1;
 pub const DEFAULT_HISTORY_LIMIT: usize = 25;
+pub const DEFAULT_FLOOD_WAIT_MAX_SECONDS: u64 = 60;
+pub const DEFAULT_HISTORY_HARD_CAP: usize = 500;
+pub const DEFAULT_HISTORY_FETCH_TIMEOUT_SECONDS: u64 = 30;
+pub const DEFAULT_SHUTDOWN_GRACE_SECONDS: u64 = 10;
+pub const DEFAULT_MAX_CONCURRENT_DRAFTS: usize = 3;
+pub const DEFAULT_AUTO_FEWSHOT_COUNT: usize = 3;
+pub const DEFAULT_REQUEST_ID_HEADER: &str = "X-Request-Id";
+pub const DEFAULT_EMPTY_CHOICES_RETRIES: usize = 1;
+pub const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 60;
+pub const DEFAULT_TYPING_MAX_SECONDS: u64 = 10;
+pub const DEFAULT_BOT_POLL_MAX_FAILURES: u32 = 10;
+const DEFAULT_RECENT_REPETITION_COUNT: usize = 5;
+pub const DEFAULT_DRAFT_TEMPLATE: &str =
+  "*AI Draft Suggestion for @{name}*\n{label}\n{reply}\n\n";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -18,25 +39,142 @@ pub struct Config {
   pub ai: AiConfig,
   pub settings: Settings,
   #[serde(default)]
+  pub ui: UiConfig,
+  #[serde(default)]
   pub users: Vec<TrackedUser>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TelegramConfig {
   pub api_id: i32,
+  #[serde(default)]
   pub api_hash: String,
+  #[serde(default)]
+  pub api_hash_file: Option<String>,
+  #[serde(default)]
   pub bot_token: String,
+  #[serde(default)]
+  pub bot_token_file: Option<String>,
+  #[serde(default = "default_bot_max_retries")]
+  pub bot_max_retries: u32,
+  #[serde(default = "default_bot_poll_max_failures")]
+  pub bot_poll_max_failures: u32,
+  #[serde(default = "default_bot_rate_limit")]
+  pub bot_rate_limit: u32,
+  /// SOCKS5 proxy for the MTProto connection, e.g. `socks5://host:port`.
+  #[serde(default)]
+  pub proxy: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AiConfig {
+  #[serde(default)]
   pub api_key: String,
+  #[serde(default)]
+  pub api_key_file: Option<String>,
   pub api_url: String,
-  pub models: Vec<String>,
+  #[serde(default)]
+  pub provider: Provider,
+  #[serde(default)]
+  pub auth: Option<AuthMode>,
+  #[serde(alias = "model", deserialize_with = "one_or_many")]
+  pub models: Vec<ModelEntry>,
   #[serde(default = "default_temperature")]
   pub temperature: f32,
+  #[serde(default, alias = "system_prompt")]
+  pub base_system_prompt: Option<String>,
+  #[serde(default)]
+  pub base_system_prompt_file: Option<String>,
+  #[serde(default)]
+  pub sticky_model: bool,
+  #[serde(default)]
+  pub user_tag: Option<String>,
+  #[serde(default)]
+  pub isolate_llm_runtime: bool,
+  #[serde(default)]
+  pub send_request_id: bool,
+  #[serde(default = "default_request_id_header")]
+  pub request_id_header: String,
   #[serde(default)]
-  pub system_prompt: Option<String>,
+  pub refine: bool,
+  #[serde(default)]
+  pub critic_model: Option<String>,
+  #[serde(default = "default_empty_choices_retries")]
+  pub empty_choices_retries: usize,
+  #[serde(default = "default_request_timeout")]
+  pub request_timeout_seconds: u64,
+  #[serde(default)]
+  pub frequency_penalty: Option<f32>,
+  #[serde(default)]
+  pub presence_penalty: Option<f32>,
+  #[serde(default)]
+  pub max_tokens: Option<u32>,
+  #[serde(default)]
+  pub top_p: Option<f32>,
+}
+
+/// A `models` entry: either just the model name or a table with its limits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ModelEntry {
+  Name(String),
+  Detailed {
+    name: String,
+    /// Most requests to this model allowed in flight at once.
+    #[serde(default)]
+    max_concurrency: Option<usize>,
+  },
+}
+
+impl ModelEntry {
+  pub fn name(&self) -> &str {
+    match self {
+      ModelEntry::Name(name) | ModelEntry::Detailed { name, .. } => name,
+    }
+  }
+
+  pub fn max_concurrency(&self) -> Option<usize> {
+    match self {
+      ModelEntry::Name(_) => None,
+      ModelEntry::Detailed { max_concurrency, .. } => *max_concurrency,
+    }
+  }
+}
+
+/// Accepts a single `model = "..."` as well as a `models` list.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<ModelEntry>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum OneOrMany {
+    One(ModelEntry),
+    Many(Vec<ModelEntry>),
+  }
+
+  Ok(match OneOrMany::deserialize(deserializer)? {
+    OneOrMany::One(model) => vec![model],
+    OneOrMany::Many(models) => models,
+  })
+}
+
+impl AiConfig {
+  /// The fallback chain, in order.
+  pub fn model_names(&self) -> Vec<String> {
+    self.models.iter().map(|model| model.name().to_string()).collect()
+  }
+
+  /// `max_concurrency` of each model that has one.
+  pub fn concurrency_limits(&self) -> HashMap<String, usize> {
+    self
+      .models
+      .iter()
+      .filter_map(|model| {
+        Some((model.name().to_string(), model.max_concurrency()?))
+      })
+      .collect()
+  }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,6 +185,168 @@ pub struct Settings {
   pub debounce_seconds: u64,
   #[serde(default = "default_history_limit")]
   pub history_limit: usize,
+  #[serde(default = "default_history_hard_cap")]
+  pub history_hard_cap: usize,
+  #[serde(default = "default_history_fetch_timeout")]
+  pub history_fetch_timeout_seconds: u64,
+  #[serde(default)]
+  pub max_history_chars: Option<usize>,
+  #[serde(default)]
+  pub include_timestamps: bool,
+  #[serde(default)]
+  pub history_ignore_patterns: Vec<String>,
+  /// The compiled `history_ignore_patterns`, filled in on load.
+  #[serde(skip)]
+  pub history_ignore_regexes: Vec<Regex>,
+  #[serde(default = "default_flood_wait_max")]
+  pub flood_wait_max_seconds: u64,
+  #[serde(default)]
+  pub anonymize_logs: bool,
+  #[serde(default)]
+  pub pause_freezes_cards: bool,
+  #[serde(default)]
+  pub forward_trigger_message: bool,
+  #[serde(default)]
+  pub validate_users_on_start: bool,
+  #[serde(default)]
+  pub idle_shutdown_seconds: Option<u64>,
+  #[serde(default = "default_shutdown_grace")]
+  pub shutdown_grace_seconds: u64,
+  #[serde(default = "default_max_concurrent_drafts")]
+  pub max_concurrent_drafts: usize,
+  #[serde(default)]
+  pub suggestions_mode: bool,
+  #[serde(default)]
+  pub duplicate_user_policy: DuplicateUserPolicy,
+  #[serde(default)]
+  pub wait_for_typing: bool,
+  #[serde(default)]
+  pub typing_indicator: bool,
+  #[serde(default)]
+  pub notes_file: Option<String>,
+  #[serde(default)]
+  pub notes_ttl_hours: Option<u64>,
+  #[serde(default = "default_sanitize_output")]
+  pub sanitize_output: bool,
+  #[serde(default = "default_normalize_whitespace")]
+  pub normalize_whitespace: bool,
+  #[serde(default)]
+  pub catchup_on_start: bool,
+  #[serde(default)]
+  pub send_formatting: bool,
+  #[serde(default)]
+  pub avoid_rejected_drafts: bool,
+  #[serde(default)]
+  pub rephrase_state_file: Option<String>,
+  #[serde(default)]
+  pub rephrase_timeout_seconds: Option<u64>,
+  #[serde(default = "default_draft_ttl")]
+  pub draft_ttl_seconds: u64,
+  #[serde(default)]
+  pub draft_history_handling: DraftHistoryHandling,
+  #[serde(default)]
+  pub match_user_language: bool,
+  #[serde(default)]
+  pub injection_guard: bool,
+  #[serde(default)]
+  pub dry_run: bool,
+  #[serde(default)]
+  pub notify_webhook: Option<String>,
+  #[serde(default)]
+  pub metrics_addr: Option<String>,
+  #[serde(default)]
+  pub tone_selector: bool,
+  #[serde(default)]
+  pub proxy: Option<String>,
+  /// Chat the bot posts draft cards to instead of our own chat with it.
+  #[serde(default)]
+  pub review_chat_id: Option<i64>,
+  #[serde(default)]
+  pub stream_drafts: bool,
+  #[serde(default)]
+  pub detect_blocked: bool,
+  #[serde(default)]
+  pub split_replies: bool,
+  #[serde(default)]
+  pub simulate_typing: bool,
+  #[serde(default = "default_typing_max_seconds")]
+  pub typing_max_seconds: u64,
+  #[serde(default)]
+  pub tune_penalties: bool,
+  #[serde(default)]
+  pub merge_users: MergeUsers,
+  #[serde(default)]
+  pub daily_token_budget: Option<u64>,
+  #[serde(default)]
+  pub monthly_token_budget: Option<u64>,
+  #[serde(default = "default_usage_file")]
+  pub usage_file: String,
+  #[serde(default = "default_mute_file")]
+  pub mute_file: String,
+  #[serde(default = "default_tuning_file")]
+  pub tuning_file: String,
+}
+
+/// How the `[[users]]` of layered config files combine.
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeUsers {
+  /// The last file listing users wins.
+  #[default]
+  Replace,
+  /// Users of all files are kept, in file order.
+  Append,
+}
+
+/// Wording of draft cards. The template is MarkdownV2 with `{name}`,
+/// `{reply}` and `{label}` (e.g. "(Rephrased)" and a line break, or nothing)
+/// placeholders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiConfig {
+  pub approve_label: String,
+  pub rephrase_label: String,
+  pub reject_label: String,
+  pub draft_template: String,
+}
+
+impl Default for UiConfig {
+  fn default() -> Self {
+    Self {
+      approve_label: "✅ Approve".to_string(),
+      rephrase_label: "🔄 Rephrase".to_string(),
+      reject_label: "❌ Reject".to_string(),
+      draft_template: DEFAULT_DRAFT_TEMPLATE.to_string(),
+    }
+  }
+}
+
+/// What to do when several `[[users]]` entries share an id.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateUserPolicy {
+  /// Refuse to start.
+  Error,
+  /// Log a warning and keep the first entry.
+  #[default]
+  Warn,
+}
+
+/// How messages sent from approved drafts are shown to the model in history.
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DraftHistoryHandling {
+  /// Like any other message of the owner's.
+  #[default]
+  Keep,
+  /// Prefixed with `[ai-drafted]`.
+  Label,
+  /// Left out.
+  Exclude,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,23 +355,201 @@ pub struct TrackedUser {
   pub name: String,
   #[serde(default)]
   pub system_prompt: String,
+  #[serde(default)]
+  pub never_initiate: bool,
+  #[serde(default)]
+  pub auto_fewshot_from_approved: bool,
+  #[serde(default = "default_auto_fewshot_count")]
+  pub auto_fewshot_count: usize,
+  #[serde(default)]
+  pub avoid_recent_repetition: bool,
+  #[serde(default = "default_recent_repetition_count")]
+  pub recent_repetition_count: usize,
+  #[serde(default)]
+  pub reply_prefix: Option<String>,
+  #[serde(default)]
+  pub temperature: Option<f32>,
+  #[serde(default)]
+  pub models: Option<Vec<String>>,
+  /// Endpoint for this user's drafts, e.g. a local model for a sensitive
+  /// contact, with its own key and wire format.
+  #[serde(default)]
+  pub api_url: Option<String>,
+  #[serde(default)]
+  pub api_key: Option<String>,
+  #[serde(default)]
+  pub provider: Option<Provider>,
+  /// Bot API id of a group (`-123` or `-100123`) to track the user in
+  /// instead of their private chat.
+  #[serde(default)]
+  pub chat_id: Option<i64>,
+  /// Whether drafts are made for the user, toggled by `/mute` and `/unmute`.
+  #[serde(default = "default_enabled")]
+  pub enabled: bool,
+  /// Send this user's drafts straight away instead of asking for approval.
+  #[serde(default)]
+  pub auto_approve: bool,
+  /// Hand-written replies to show the model how to answer this user.
+  #[serde(default)]
+  pub examples: Vec<Example>,
+}
+
+/// A message from a tracked user and how the owner would reply to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Example {
+  pub incoming: String,
+  pub reply: String,
+}
+
+impl Default for TrackedUser {
+  fn default() -> Self {
+    Self {
+      id: 0,
+      name: String::new(),
+      system_prompt: String::new(),
+      never_initiate: false,
+      auto_fewshot_from_approved: false,
+      auto_fewshot_count: DEFAULT_AUTO_FEWSHOT_COUNT,
+      avoid_recent_repetition: false,
+      recent_repetition_count: DEFAULT_RECENT_REPETITION_COUNT,
+      reply_prefix: None,
+      temperature: None,
+      models: None,
+      api_url: None,
+      api_key: None,
+      provider: None,
+      chat_id: None,
+      enabled: true,
+      auto_approve: false,
+      examples: Vec::new(),
+    }
+  }
 }
 
 impl TrackedUser {
-  #[allow(dead_code)]
   pub fn user_id(&self) -> PeerId {
     PeerId::user(self.id)
   }
 
-  pub fn chat_id(&self) -> PeerId {
-    PeerId::chat(self.id)
+  /// The chat drafts for this user are about: the group from `chat_id` or
+  /// their private chat.
+  pub fn peer_id(&self) -> PeerId {
+    match self.chat_id {
+      Some(id) => group_peer_id(id).expect("chat_id checked on load"),
+      None => self.user_id(),
+    }
+  }
+}
+
+/// Converts a Bot API group id into a chat or channel peer, see
+/// https://core.telegram.org/api/bots/ids.
+fn group_peer_id(id: i64) -> Option<PeerId> {
+  const CHANNEL_OFFSET: i64 = 1_000_000_000_000;
+  let id = id.checked_neg()?;
+  match id.checked_sub(CHANNEL_OFFSET) {
+    Some(
+      channel @ (1..=997_852_516_352 | 1_002_147_483_649..=3_000_000_000_000),
+    ) => Some(PeerId::channel(channel)),
+    _ if (1..=999_999_999_999).contains(&id) => Some(PeerId::chat(id)),
+    _ => None,
+  }
+}
+
+/// Environment variables to read instead of the process's, if any.
+type Env = Option<config::Map<String, String>>;
+
+fn read_secret(
+  secret: &mut String,
+  file: &Option<String>,
+  field: &str,
+) -> Result<()> {
+  if let Some(path) = file {
+    let contents = fs::read_to_string(path).with_context(|| {
+      format!("Failed to read {} from file: {}", field, path)
+    })?;
+    *secret = contents.trim_end_matches(['\r', '\n']).to_string();
+  }
+  Ok(())
+}
+
+fn expand_env_var(secret: &mut String, field: &str, env: &Env) -> Result<()> {
+  if let Some(var) =
+    secret.trim().strip_prefix("${").and_then(|var| var.strip_suffix('}'))
+  {
+    let value = match env {
+      Some(env) => env.get(var).cloned(),
+      None => std::env::var(var).ok(),
+    };
+    *secret = value.with_context(|| {
+      format!("Environment variable {} for {} is not set", var, field)
+    })?;
   }
+  Ok(())
+}
+
+fn default_bot_max_retries() -> u32 {
+  crate::bot::DEFAULT_MAX_RETRIES
+}
+
+fn default_bot_rate_limit() -> u32 {
+  crate::bot::DEFAULT_RATE_LIMIT
+}
+
+fn default_bot_poll_max_failures() -> u32 {
+  DEFAULT_BOT_POLL_MAX_FAILURES
 }
 
+/// Sampling temperatures OpenAI-compatible APIs accept.
+const TEMPERATURE_RANGE: std::ops::RangeInclusive<f32> = 0.0..=2.0;
+
 fn default_temperature() -> f32 {
   1.5
 }
 
+fn default_request_id_header() -> String {
+  DEFAULT_REQUEST_ID_HEADER.to_string()
+}
+
+fn default_empty_choices_retries() -> usize {
+  DEFAULT_EMPTY_CHOICES_RETRIES
+}
+
+fn default_request_timeout() -> u64 {
+  DEFAULT_REQUEST_TIMEOUT_SECONDS
+}
+
+fn default_typing_max_seconds() -> u64 {
+  DEFAULT_TYPING_MAX_SECONDS
+}
+
+fn default_sanitize_output() -> bool {
+  true
+}
+
+fn default_normalize_whitespace() -> bool {
+  true
+}
+
+fn default_draft_ttl() -> u64 {
+  7 * 24 * 60 * 60
+}
+
+fn default_enabled() -> bool {
+  true
+}
+
+fn default_mute_file() -> String {
+  "mutes.json".to_string()
+}
+
+fn default_tuning_file() -> String {
+  "tuning.json".to_string()
+}
+
+fn default_usage_file() -> String {
+  "usage.json".to_string()
+}
+
 fn default_session_file() -> String {
   DEFAULT_SESSION_FILE.to_string()
 }
@@ -84,26 +562,591 @@ fn default_history_limit() -> usize {
   DEFAULT_HISTORY_LIMIT
 }
 
+fn default_auto_fewshot_count() -> usize {
+  DEFAULT_AUTO_FEWSHOT_COUNT
+}
+
+fn default_recent_repetition_count() -> usize {
+  DEFAULT_RECENT_REPETITION_COUNT
+}
+
+fn default_history_hard_cap() -> usize {
+  DEFAULT_HISTORY_HARD_CAP
+}
+
+fn default_history_fetch_timeout() -> u64 {
+  DEFAULT_HISTORY_FETCH_TIMEOUT_SECONDS
+}
+
+fn default_shutdown_grace() -> u64 {
+  DEFAULT_SHUTDOWN_GRACE_SECONDS
+}
+
+fn default_max_concurrent_drafts() -> usize {
+  DEFAULT_MAX_CONCURRENT_DRAFTS
+}
+
+fn default_flood_wait_max() -> u64 {
+  DEFAULT_FLOOD_WAIT_MAX_SECONDS
+}
+
+impl Settings {
+  /// Compiles `history_ignore_patterns` into `history_ignore_regexes`.
+  fn compile_history_ignore_patterns(&mut self) -> Result<()> {
+    self.history_ignore_regexes = self
+      .history_ignore_patterns
+      .iter()
+      .map(|pattern| {
+        Regex::new(pattern).with_context(|| {
+          format!("Invalid history_ignore_patterns entry: {}", pattern)
+        })
+      })
+      .collect::<Result<_>>()?;
+    Ok(())
+  }
+}
+
 impl Config {
   pub fn load(path: impl AsRef<Path>) -> Result<Self> {
-    let path = path.as_ref();
+    Self::load_layered(&[path])
+  }
+
+  /// Loads `paths` in order, each file overriding the keys set by the ones
+  /// before it. `[[users]]` lists replace each other unless `merge_users`
+  /// (as merged) says to append them.
+  pub fn load_layered(paths: &[impl AsRef<Path>]) -> Result<Self> {
+    Self::load_with_env(paths, None)
+  }
 
-    let config = ConfigBuilder::builder()
-      .add_source(config::File::from(path))
+  /// [`Config::load_layered`] with the variables of `env` in place of the
+  /// process environment, if given.
+  fn load_with_env(paths: &[impl AsRef<Path>], env: Env) -> Result<Self> {
+    let mut layers = Vec::with_capacity(paths.len());
+    let mut builder = ConfigBuilder::builder();
+    for path in paths {
+      let path = path.as_ref();
+      let layer = ConfigBuilder::builder()
+        .add_source(config::File::from(path))
+        .build()
+        .with_context(|| {
+          format!("Failed to load config file: {}", path.display())
+        })?;
+      builder = builder.add_source(layer.clone());
+      layers.push((path, layer));
+    }
+
+    // e.g. MILLAMA_AI__API_KEY overrides `api_key` of `[ai]`
+    builder = builder.add_source(
+      config::Environment::with_prefix("MILLAMA")
+        .prefix_separator("_")
+        .separator("__")
+        .try_parsing(true)
+        .source(env.clone()),
+    );
+
+    let names = || {
+      let names: Vec<_> =
+        paths.iter().map(|path| path.as_ref().display().to_string()).collect();
+      names.join(", ")
+    };
+    let config = builder
       .build()
-      .with_context(|| {
-        format!("Failed to load config file: {}", path.display())
-      })?;
+      .with_context(|| format!("Failed to merge config files: {}", names()))?;
+    let mut config: Config = config
+      .try_deserialize()
+      .with_context(|| format!("Failed to parse config file: {}", names()))?;
 
-    let config: Config = config.try_deserialize().with_context(|| {
-      format!("Failed to parse config file: {}", path.display())
-    })?;
+    if config.settings.merge_users == MergeUsers::Append {
+      config.users.clear();
+      for (path, layer) in &layers {
+        match layer.get::<Vec<TrackedUser>>("users") {
+          Ok(users) => config.users.extend(users),
+          Err(config::ConfigError::NotFound(_)) => {}
+          Err(e) => {
+            return Err(e).with_context(|| {
+              format!("Failed to parse users in: {}", path.display())
+            });
+          }
+        }
+      }
+    }
+
+    config.read_secret_vars(&env)?;
+    config.read_secret_files()?;
+    config.read_base_prompt_file()?;
+    config.dedupe_users()?;
+    config.check_chat_ids()?;
+    config.settings.compile_history_ignore_patterns()?;
+    anyhow::ensure!(
+      config.ui.draft_template.contains("{reply}"),
+      "draft_template must contain {{reply}}"
+    );
 
     Ok(config)
   }
 
+  /// Replaces `${VAR}` secrets with the environment variable's value.
+  fn read_secret_vars(&mut self, env: &Env) -> Result<()> {
+    expand_env_var(&mut self.telegram.api_hash, "api_hash", env)?;
+    expand_env_var(&mut self.telegram.bot_token, "bot_token", env)?;
+    expand_env_var(&mut self.ai.api_key, "api_key", env)?;
+    Ok(())
+  }
+
+  /// Replaces inline secrets with the contents of their `*_file` paths.
+  fn read_secret_files(&mut self) -> Result<()> {
+    let telegram = &mut self.telegram;
+    read_secret(&mut telegram.api_hash, &telegram.api_hash_file, "api_hash")?;
+    read_secret(
+      &mut telegram.bot_token,
+      &telegram.bot_token_file,
+      "bot_token",
+    )?;
+    read_secret(&mut self.ai.api_key, &self.ai.api_key_file, "api_key")?;
+    Ok(())
+  }
+
+  /// Replaces the inline `base_system_prompt` with the file's contents.
+  fn read_base_prompt_file(&mut self) -> Result<()> {
+    if let Some(path) = &self.ai.base_system_prompt_file {
+      let prompt = fs::read_to_string(path).with_context(|| {
+        format!("Failed to read base_system_prompt from file: {}", path)
+      })?;
+      self.ai.base_system_prompt = Some(prompt.trim_end().to_string());
+    }
+    Ok(())
+  }
+
+  /// Applies `duplicate_user_policy` to `[[users]]` entries sharing an id.
+  fn dedupe_users(&mut self) -> Result<()> {
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    self.users.retain(|user| {
+      let first = seen.insert(user.id);
+      if !first {
+        duplicates.push(user.id);
+      }
+      first
+    });
+
+    if duplicates.is_empty() {
+      return Ok(());
+    }
+    match self.settings.duplicate_user_policy {
+      DuplicateUserPolicy::Error => {
+        anyhow::bail!("Duplicate tracked user ids: {:?}", duplicates)
+      }
+      DuplicateUserPolicy::Warn => {
+        warn!(
+          "Duplicate tracked user ids {:?}, keeping the first entry of each",
+          duplicates
+        );
+        Ok(())
+      }
+    }
+  }
+
+  fn check_chat_ids(&self) -> Result<()> {
+    for user in &self.users {
+      if let Some(id) = user.chat_id
+        && group_peer_id(id).is_none()
+      {
+        anyhow::bail!(
+          "Invalid chat_id {} for user {}: expected a group id like -100123",
+          id,
+          user.id
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks what would otherwise only fail once the first reply is drafted
+  /// or sent, naming the offending field.
+  pub fn validate(&self) -> Result<()> {
+    anyhow::ensure!(
+      !self.ai.models.is_empty(),
+      "ai.models is empty: configure at least one model"
+    );
+    anyhow::ensure!(
+      TEMPERATURE_RANGE.contains(&self.ai.temperature),
+      "ai.temperature {} is out of range: expected 0.0 to 2.0",
+      self.ai.temperature
+    );
+    anyhow::ensure!(
+      !self.telegram.bot_token.trim().is_empty(),
+      "telegram.bot_token is missing: the approval bot needs one"
+    );
+    anyhow::ensure!(
+      self.settings.max_concurrent_drafts > 0,
+      "settings.max_concurrent_drafts is 0: no draft could ever be generated"
+    );
+
+    if let Some(proxy) = &self.telegram.proxy {
+      let url = reqwest::Url::parse(proxy).ok();
+      anyhow::ensure!(
+        url.is_some_and(|url| {
+          url.scheme() == "socks5" && url.has_host() && url.port().is_some()
+        }),
+        "telegram.proxy {:?} is invalid: expected socks5://host:port",
+        proxy
+      );
+    }
+
+    let mut seen = HashSet::new();
+    for user in &self.users {
+      anyhow::ensure!(
+        seen.insert(user.id),
+        "users: id {} is listed more than once",
+        user.id
+      );
+      if let Some(temperature) = user.temperature {
+        anyhow::ensure!(
+          TEMPERATURE_RANGE.contains(&temperature),
+          "users: temperature {} for user {} is out of range: expected 0.0 \
+           to 2.0",
+          temperature,
+          user.id
+        );
+      }
+    }
+    Ok(())
+  }
+
   pub fn users_map(&self) -> HashMap<PeerId, TrackedUser> {
-    // Map chat IDs for matching incoming messages
-    self.users.iter().map(|user| (user.chat_id(), user.clone())).collect()
+    // Keyed by user peer, which is what private messages come from
+    self.users.iter().map(|user| (user.user_id(), user.clone())).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::testing::temp_path, std::path::PathBuf};
+
+  const CONFIG: &str = r#"
+[telegram]
+api_id = 1
+api_hash = "hash"
+bot_token = "token"
+
+[ai]
+api_key = "inline"
+api_url = "http://localhost"
+models = ["model"]
+
+[settings]
+"#;
+
+  fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = temp_path(name);
+    fs::write(&path, contents).unwrap();
+    path
+  }
+
+  #[test]
+  fn test_api_key_from_file() {
+    let key = temp_file("api_key", "secret-key\n");
+    let config = CONFIG.replace(
+      "[settings]",
+      &format!("api_key_file = {:?}\n\n[settings]", key.display()),
+    );
+    let config = Config::load(temp_file("secret.toml", &config)).unwrap();
+
+    assert_eq!(config.ai.api_key, "secret-key");
+    assert_eq!(config.telegram.bot_token, "token");
+  }
+
+  #[test]
+  fn test_duplicate_users_follow_policy() {
+    let users = r#"
+[[users]]
+id = 1
+name = "first"
+
+[[users]]
+id = 1
+name = "second"
+"#;
+
+    let config = format!("{CONFIG}{users}");
+    let config = Config::load(temp_file("dupes.toml", &config)).unwrap();
+    assert_eq!(config.users.len(), 1);
+    assert_eq!(config.users[0].name, "first");
+
+    let config = CONFIG
+      .replace("[settings]", "[settings]\nduplicate_user_policy = \"error\"");
+    let config = format!("{config}{users}");
+    let err = Config::load(temp_file("dupes-error.toml", &config)).unwrap_err();
+    assert!(err.to_string().contains("Duplicate tracked user ids: [1]"));
+  }
+
+  #[test]
+  fn test_models_accept_concurrency_limits() {
+    let config = CONFIG.replace(
+      r#"models = ["model"]"#,
+      r#"models = ["local", { name = "strict", max_concurrency = 1 }]"#,
+    );
+    let config = Config::load(temp_file("limits.toml", &config)).unwrap();
+
+    assert_eq!(config.ai.model_names(), ["local", "strict"]);
+    assert_eq!(
+      config.ai.concurrency_limits(),
+      HashMap::from([("strict".to_string(), 1)])
+    );
+  }
+
+  #[test]
+  fn test_auth_mode_is_read_from_the_table() {
+    let config = Config::load(temp_file("bearer.toml", CONFIG)).unwrap();
+    assert_eq!(config.ai.auth, None);
+
+    let config = CONFIG.replace(
+      "[settings]",
+      "auth = { type = \"query\", param = \"key\" }\n\n[settings]",
+    );
+    let config = Config::load(temp_file("query.toml", &config)).unwrap();
+    assert_eq!(config.ai.auth, Some(AuthMode::Query { param: "key".into() }));
+  }
+
+  #[test]
+  fn test_single_model_is_still_accepted() {
+    let config = CONFIG
+      .replace(r#"models = ["model"]"#, r#"model = "legacy""#)
+      .replace("[settings]", "base_system_prompt = \"Be brief\"\n\n[settings]");
+    let config = Config::load(temp_file("single.toml", &config)).unwrap();
+    assert_eq!(config.ai.model_names(), ["legacy"]);
+    assert_eq!(config.ai.base_system_prompt.as_deref(), Some("Be brief"));
+
+    let config = CONFIG
+      .replace(r#"models = ["model"]"#, r#"models = ["first", "second"]"#);
+    let config = Config::load(temp_file("many.toml", &config)).unwrap();
+    assert_eq!(config.ai.model_names(), ["first", "second"]);
+    assert!(config.ai.base_system_prompt.is_none());
+  }
+
+  #[test]
+  fn test_base_prompt_from_file() {
+    let prompt = temp_file("base_prompt", "Never share addresses.\nBe kind.\n");
+    let config = CONFIG.replace(
+      "[settings]",
+      &format!(
+        "base_system_prompt = \"inline\"\nbase_system_prompt_file = {:?}\n\n\
+         [settings]",
+        prompt.display()
+      ),
+    );
+    let config = Config::load(temp_file("prompt.toml", &config)).unwrap();
+
+    assert_eq!(
+      config.ai.base_system_prompt.as_deref(),
+      Some("Never share addresses.\nBe kind.")
+    );
+  }
+
+  #[test]
+  fn test_user_temperature_is_optional() {
+    let users = r#"
+[[users]]
+id = 1
+name = "strict"
+temperature = 0.2
+models = ["precise"]
+
+[[users]]
+id = 2
+name = "default"
+"#;
+
+    let config = format!("{CONFIG}{users}");
+    let config = Config::load(temp_file("user-ai.toml", &config)).unwrap();
+    assert_eq!(config.users[0].temperature, Some(0.2));
+    assert_eq!(
+      config.users[0].models.as_deref(),
+      Some(&["precise".into()][..])
+    );
+    assert_eq!(config.users[1].temperature, None);
+    assert_eq!(config.users[1].models, None);
+  }
+
+  #[test]
+  fn test_layered_configs_merge_in_order() {
+    let base = format!("{CONFIG}\n[[users]]\nid = 1\nname = \"base\"\n");
+    let base = temp_file("layer-base.toml", &base);
+    let local = r#"
+[ai]
+api_key = "local-secret"
+
+[settings]
+debounce_seconds = 5
+
+[[users]]
+id = 2
+name = "local"
+"#;
+    let local_path = temp_file("layer-local.toml", local);
+
+    let config = Config::load_layered(&[&base, &local_path]).unwrap();
+    assert_eq!(config.ai.api_key, "local-secret");
+    assert_eq!(config.ai.api_url, "http://localhost");
+    assert_eq!(config.settings.debounce_seconds, 5);
+    let names: Vec<_> = config.users.iter().map(|u| u.name.as_str()).collect();
+    assert_eq!(names, ["local"]);
+
+    let local = local.replace(
+      "debounce_seconds = 5",
+      "debounce_seconds = 5\nmerge_users = \"append\"",
+    );
+    let local_path = temp_file("layer-append.toml", &local);
+    let config = Config::load_layered(&[&base, &local_path]).unwrap();
+    let names: Vec<_> = config.users.iter().map(|u| u.name.as_str()).collect();
+    assert_eq!(names, ["base", "local"]);
+  }
+
+  #[test]
+  fn test_chat_id_picks_peer_kind() {
+    let user = |chat_id| TrackedUser { id: 7, chat_id, ..Default::default() };
+    assert_eq!(user(None).peer_id(), PeerId::user(7));
+    assert_eq!(user(Some(-4567)).peer_id(), PeerId::chat(4567));
+    assert_eq!(user(Some(-1004567)).peer_id(), PeerId::chat(1004567));
+    assert_eq!(
+      user(Some(-1001234567890)).peer_id(),
+      PeerId::channel(1234567890)
+    );
+
+    let config =
+      format!("{CONFIG}\n[[users]]\nid = 7\nname = \"a\"\nchat_id = 42\n");
+    let path = temp_file("chat-id.toml", &config);
+    let err = Config::load(&path).unwrap_err();
+    assert!(err.to_string().contains("Invalid chat_id 42"));
+  }
+
+  #[test]
+  fn test_draft_template_needs_reply() {
+    let path = temp_file(
+      "template.toml",
+      &format!("{}\n[ui]\ndraft_template = \"{{name}}\"\n", CONFIG),
+    );
+    let err = Config::load(&path).unwrap_err();
+    assert!(err.to_string().contains("{reply}"));
+    let _ = fs::remove_file(&path);
+  }
+
+  #[test]
+  fn test_secrets_from_env_vars() {
+    let env = config::Map::from_iter(
+      [
+        ("TEST_MILLAMA_BOT_TOKEN", "env-token"),
+        ("MILLAMA_AI__USER_TAG", "from-env"),
+      ]
+      .map(|(var, value)| (var.to_string(), value.to_string())),
+    );
+    let config = CONFIG.replace(r#""token""#, r#""${TEST_MILLAMA_BOT_TOKEN}""#);
+    let path = temp_file("env.toml", &config);
+    let config = Config::load_with_env(&[path], Some(env)).unwrap();
+
+    assert_eq!(config.telegram.bot_token, "env-token");
+    assert_eq!(config.ai.user_tag.as_deref(), Some("from-env"));
+    assert_eq!(config.ai.api_key, "inline");
+  }
+
+  #[test]
+  fn test_missing_env_var_errors() {
+    let config = CONFIG.replace(r#""hash""#, r#""${TEST_MILLAMA_UNSET}""#);
+    let err = Config::load(temp_file("unset.toml", &config)).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Environment variable TEST_MILLAMA_UNSET for api_hash is not set"
+    );
+  }
+
+  #[test]
+  fn test_invalid_ignore_pattern_errors() {
+    let config = format!("{}history_ignore_patterns = [\"(\"]\n", CONFIG);
+    let err = Config::load(temp_file("pattern.toml", &config)).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid history_ignore_patterns entry: (");
+
+    let config = format!("{}history_ignore_patterns = [\"^otp\"]\n", CONFIG);
+    let config = Config::load(temp_file("pattern.toml", &config)).unwrap();
+    let regexes = &config.settings.history_ignore_regexes;
+    assert!(regexes.len() == 1 && regexes[0].is_match("otp 1234"));
+  }
+
+  fn validate(name: &str, config: &str) -> Result<()> {
+    Config::load(temp_file(name, config))?.validate()
+  }
+
+  #[test]
+  fn test_valid_config_passes_validation() {
+    validate("valid.toml", CONFIG).unwrap();
+  }
+
+  #[test]
+  fn test_empty_models_fail_validation() {
+    let config = CONFIG.replace(r#"models = ["model"]"#, "models = []");
+    let err = validate("no-models.toml", &config).unwrap_err();
+    assert!(err.to_string().starts_with("ai.models is empty"), "{}", err);
+  }
+
+  #[test]
+  fn test_telegram_proxy_must_be_socks5() {
+    let with_proxy =
+      |proxy| CONFIG.replace("[ai]", &format!("proxy = {:?}\n[ai]", proxy));
+    validate("socks5.toml", &with_proxy("socks5://user:pw@127.0.0.1:1080"))
+      .unwrap();
+    for proxy in ["http://127.0.0.1:8080", "socks5://127.0.0.1", "nope"] {
+      let err = validate("bad-proxy.toml", &with_proxy(proxy)).unwrap_err();
+      assert!(err.to_string().starts_with("telegram.proxy"), "{}", err);
+    }
+  }
+
+  #[test]
+  fn test_temperature_out_of_range_fails_validation() {
+    let config = CONFIG.replace("[settings]", "temperature = 2.5\n[settings]");
+    let err = validate("temperature.toml", &config).unwrap_err();
+    assert!(err.to_string().starts_with("ai.temperature 2.5"), "{}", err);
+
+    let config = format!(
+      "{}\n[[users]]\nid = 5\nname = \"a\"\ntemperature = -0.1\n",
+      CONFIG
+    );
+    let err = validate("user-temperature.toml", &config).unwrap_err();
+    assert!(err.to_string().contains("for user 5"), "{}", err);
+  }
+
+  #[test]
+  fn test_missing_bot_token_fails_validation() {
+    let config = CONFIG.replace("bot_token = \"token\"\n", "");
+    let err = validate("no-token.toml", &config).unwrap_err();
+    assert!(err.to_string().starts_with("telegram.bot_token"), "{}", err);
+  }
+
+  #[test]
+  fn test_zero_concurrent_drafts_fails_validation() {
+    let config =
+      CONFIG.replace("[settings]", "[settings]\nmax_concurrent_drafts = 0");
+    let err = validate("no-drafts.toml", &config).unwrap_err();
+    assert!(err.to_string().starts_with("settings.max_concurrent_drafts"));
+  }
+
+  #[test]
+  fn test_duplicate_user_ids_fail_validation() {
+    let mut config = Config::load(temp_file("dup.toml", CONFIG)).unwrap();
+    let user = TrackedUser { id: 7, ..TrackedUser::default() };
+    config.users = vec![user.clone(), user];
+    let err = config.validate().unwrap_err();
+    assert_eq!(err.to_string(), "users: id 7 is listed more than once");
+  }
+
+  #[test]
+  fn test_missing_secret_file_errors() {
+    let config = CONFIG.replace(
+      "bot_token = \"token\"",
+      "bot_token_file = \"/nonexistent/millama/bot_token\"",
+    );
+    let err = Config::load(temp_file("missing.toml", &config)).unwrap_err();
+
+    let message = format!("{:#}", err);
+    assert!(message.contains("bot_token"), "{}", message);
+    assert!(message.contains("/nonexistent/millama/bot_token"), "{}", message);
   }
 }
@@ -0,0 +1,35 @@
+//! The HTTP client shared by the Bot API, LLM and webhook requests.
+
+use anyhow::{Context, Result};
+
+/// Builds the shared client, routing it through `proxy` if one is set.
+///
+/// Fails with an error naming the setting when it can't be used, so a typo
+/// is caught at startup rather than on the first request.
+pub fn client(proxy: Option<&str>) -> Result<reqwest::Client> {
+  let mut builder = reqwest::Client::builder();
+  if let Some(proxy) = proxy {
+    let proxy = reqwest::Proxy::all(proxy)
+      .with_context(|| format!("Invalid `proxy` setting: {:?}", proxy))?;
+    builder = builder.proxy(proxy);
+  }
+  builder.build().context("Failed to build the HTTP client")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_invalid_proxy_names_the_setting() {
+    let err = client(Some("not a proxy")).unwrap_err();
+    assert!(
+      err.to_string().contains("Invalid `proxy` setting: \"not a proxy\""),
+      "{:#}",
+      err
+    );
+
+    assert!(client(Some("http://127.0.0.1:8080")).is_ok());
+    assert!(client(None).is_ok());
+  }
+}
@@ -1,6 +1,18 @@
 pub mod bot;
+pub mod budget;
 pub mod config;
+pub mod http;
 pub mod llm;
+pub mod metrics;
+pub mod notes;
+pub mod notify;
+pub mod persist;
+pub mod redact;
+pub mod rephrase;
+pub mod text;
+
+#[cfg(test)]
+mod testing;
 
 #[cfg(test)]
 mod tests {
@@ -12,6 +24,7 @@ mod tests {
       id: 12345,
       name: "Test User".to_string(),
       system_prompt: "Be helpful".to_string(),
+      ..Default::default()
     };
 
     assert_eq!(user.user_id(), PeerId::user(12345));
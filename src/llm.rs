@@ -1,25 +1,193 @@
 use {
-  anyhow::{Result, anyhow},
+  anyhow::{Context, Result, anyhow},
   serde::{Deserialize, Serialize},
+  std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+    time::Duration,
+  },
+  tokio::{
+    runtime::{Builder, Runtime},
+    sync::{Semaphore, mpsc::UnboundedSender},
+  },
   tracing::{debug, trace, warn},
+  uuid::Uuid,
 };
 
-#[derive(Serialize, Debug, Clone)]
+/// Worker threads of the runtime LLM calls are moved to when isolated.
+const ISOLATED_WORKERS: usize = 2;
+/// Version of the Anthropic API the requests are written against.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Reply length cap Anthropic requires on every request.
+const ANTHROPIC_MAX_TOKENS: u32 = 1024;
+
+/// Which completion API `api_url` speaks.
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+  /// OpenAI-compatible chat completions.
+  #[default]
+  #[serde(rename = "openai")]
+  OpenAi,
+  /// Anthropic's messages API.
+  Anthropic,
+  /// Ollama's native chat API, for local models.
+  Ollama,
+}
+
+/// How the API key reaches `api_url`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthMode {
+  /// `Authorization: Bearer <key>`.
+  #[default]
+  Bearer,
+  /// The bare key in the header `name`, e.g. `x-goog-api-key`.
+  Header { name: String },
+  /// The key in the query parameter `param`, e.g. Gemini's `?key=`.
+  Query { param: String },
+}
+
+impl AuthMode {
+  fn apply(
+    &self,
+    request: reqwest::RequestBuilder,
+    api_key: &str,
+  ) -> reqwest::RequestBuilder {
+    match self {
+      Self::Bearer => request.bearer_auth(api_key),
+      Self::Header { name } => request.header(name, api_key),
+      Self::Query { param } => request.query(&[(param, api_key)]),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatMessage {
   pub role: String,
   pub content: String,
 }
 
+/// A generated reply along with the model in the fallback chain that wrote it.
+#[derive(Debug, Clone)]
+pub struct Completion {
+  pub text: String,
+  pub model: String,
+  /// The id sent in the request id header, if any.
+  pub request_id: Option<String>,
+  /// Tokens the provider reports for the request, 0 if it doesn't say.
+  pub tokens: u64,
+}
+
+/// A provider's answer and the tokens it says it took.
+#[derive(Debug)]
+struct Reply {
+  text: String,
+  tokens: u64,
+}
+
+/// Optional extras attached to every completion request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestOptions<'a> {
+  /// Sent as the `user` field for provider-side abuse tracking.
+  pub user: Option<&'a str>,
+  /// Header to carry a freshly generated request id in.
+  pub request_id_header: Option<&'a str>,
+  /// Per-model caps on requests in flight.
+  pub limits: Option<&'a ModelLimits>,
+  /// Extra attempts on the same model when it answers with no choices.
+  pub empty_choices_retries: usize,
+  /// Sampling penalties, left to the provider's defaults when unset.
+  pub frequency_penalty: Option<f32>,
+  pub presence_penalty: Option<f32>,
+  /// Caps the reply length, in tokens.
+  pub max_tokens: Option<u32>,
+  /// Nucleus sampling cutoff, left to the provider's default when unset.
+  pub top_p: Option<f32>,
+  /// Gives up on a request that takes longer, moving on to the next model.
+  pub timeout: Option<Duration>,
+  /// Streams the reply, sending the text received so far after every chunk.
+  pub stream: Option<&'a UnboundedSender<String>>,
+  /// The API the request is shaped for.
+  pub provider: Provider,
+  /// How the key is sent, the provider's usual way when unset.
+  pub auth: Option<&'a AuthMode>,
+}
+
+/// The provider answered without any choices, usually a transient glitch
+/// worth retrying.
+#[derive(Debug)]
+struct EmptyChoices;
+
+impl std::fmt::Display for EmptyChoices {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("No choices in response")
+  }
+}
+
+impl std::error::Error for EmptyChoices {}
+
+/// A semaphore per model with a `max_concurrency`, shared by every request to
+/// it. Models without one aren't limited.
+#[derive(Debug, Default)]
+pub struct ModelLimits {
+  semaphores: HashMap<String, Arc<Semaphore>>,
+}
+
+impl ModelLimits {
+  pub fn new(limits: HashMap<String, usize>) -> Self {
+    let semaphores = limits
+      .into_iter()
+      .map(|(model, max)| (model, Arc::new(Semaphore::new(max.max(1)))))
+      .collect();
+    Self { semaphores }
+  }
+
+  fn semaphore(&self, model: &str) -> Option<Arc<Semaphore>> {
+    self.semaphores.get(model).cloned()
+  }
+}
+
 #[derive(Serialize)]
 struct CompletionRequest {
   model: String,
   messages: Vec<ChatMessage>,
   temperature: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  user: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  frequency_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  presence_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  max_tokens: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  top_p: Option<f32>,
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  stream: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  stream_options: Option<StreamOptions>,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+  /// Asks for a last chunk carrying the token usage.
+  include_usage: bool,
 }
 
 #[derive(Deserialize)]
 struct CompletionResponse {
   choices: Vec<Choice>,
+  #[serde(default)]
+  usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+  #[serde(default)]
+  total_tokens: u64,
 }
 
 #[derive(Deserialize)]
@@ -30,6 +198,607 @@ struct Choice {
 #[derive(Deserialize)]
 struct MessageContent {
   content: String,
+  /// The chain of thought some reasoning models send apart from the reply.
+  #[serde(default)]
+  reasoning_content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamChunk {
+  #[serde(default)]
+  choices: Vec<StreamChoice>,
+  #[serde(default)]
+  usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+  delta: Delta,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+  #[serde(default)]
+  content: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+  model: &'a str,
+  max_tokens: u32,
+  #[serde(skip_serializing_if = "str::is_empty")]
+  system: &'a str,
+  messages: &'a [ChatMessage],
+  temperature: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  top_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  metadata: Option<Metadata<'a>>,
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  stream: bool,
+}
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+  user_id: &'a str,
+}
+
+#[derive(Serialize)]
+struct OllamaRequest<'a> {
+  model: &'a str,
+  messages: Vec<ChatMessage>,
+  stream: bool,
+  options: OllamaOptions,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+  temperature: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  top_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  frequency_penalty: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  presence_penalty: Option<f32>,
+  /// Ollama's name for `max_tokens`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  num_predict: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+  message: OllamaMessage,
+  #[serde(default)]
+  prompt_eval_count: u64,
+  #[serde(default)]
+  eval_count: u64,
+}
+
+#[derive(Deserialize)]
+struct OllamaMessage {
+  #[serde(default)]
+  content: String,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+  content: Vec<ContentBlock>,
+  #[serde(default)]
+  usage: MessagesUsage,
+}
+
+#[derive(Deserialize, Default)]
+struct MessagesUsage {
+  #[serde(default)]
+  input_tokens: u64,
+  #[serde(default)]
+  output_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct ContentBlock {
+  #[serde(default)]
+  text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MessagesEvent {
+  #[serde(rename = "type")]
+  kind: String,
+  #[serde(default)]
+  delta: Option<TextDelta>,
+  /// Set on `message_start`, with the input tokens.
+  #[serde(default)]
+  message: Option<MessageStart>,
+  /// Set on `message_delta`, with the output tokens so far.
+  #[serde(default)]
+  usage: Option<MessagesUsage>,
+}
+
+#[derive(Deserialize)]
+struct MessageStart {
+  #[serde(default)]
+  usage: MessagesUsage,
+}
+
+#[derive(Deserialize)]
+struct TextDelta {
+  #[serde(default)]
+  text: Option<String>,
+}
+
+/// The chain for the refinement pass: the critic model if there is one, then
+/// the model that wrote the draft as a fallback.
+pub fn refine_models(critic: Option<&str>, primary: &str) -> Vec<String> {
+  critic
+    .filter(|critic| *critic != primary)
+    .into_iter()
+    .chain([primary])
+    .map(str::to_string)
+    .collect()
+}
+
+/// `reply` without the leading `<think>...</think>` block reasoning models
+/// put before their answer. An unclosed block is left alone.
+pub fn strip_reasoning(reply: &str) -> &str {
+  let Some(thinking) = reply.trim_start().strip_prefix("<think>") else {
+    return reply;
+  };
+  match thinking.split_once("</think>") {
+    Some((_, answer)) => answer.trim_start(),
+    None => reply,
+  }
+}
+
+/// What to preview of a reply still streaming in: nothing while it's inside
+/// the leading `<think>` block, and only the answer once that block closes.
+fn reasoning_preview(partial: &str) -> Option<&str> {
+  let unclosed = partial.trim_start().starts_with("<think>")
+    && !partial.contains("</think>");
+  // A tag still arriving can't be told from the start of a reply yet
+  let opening = "<think>".starts_with(partial.trim_start());
+  (!unclosed && !opening).then(|| strip_reasoning(partial))
+}
+
+/// Sends the preview of `text` to `partial`, unless there's nothing to show.
+fn send_preview(partial: &UnboundedSender<String>, text: &str) {
+  if let Some(preview) = reasoning_preview(text) {
+    // The receiver going away only means nobody watches the preview
+    let _ = partial.send(preview.to_string());
+  }
+}
+
+/// The conversation with the draft handed back for review and rewriting.
+pub fn refine_messages(
+  mut history: Vec<ChatMessage>,
+  draft: &str,
+) -> Vec<ChatMessage> {
+  history.push(ChatMessage {
+    role: "user".into(),
+    content: format!(
+      "Here is a draft reply to the conversation above:\n\n{}\n\n\
+       Critique it against your instructions, then answer with only the \
+       improved reply.",
+      draft
+    ),
+  });
+  history
+}
+
+/// Runs `fut` on a small runtime of its own when `isolate` is set, so slow or
+/// CPU-heavy generation can't starve the threads handling Telegram updates.
+pub async fn run_isolated<F>(isolate: bool, fut: F) -> Result<F::Output>
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  if !isolate {
+    return Ok(fut.await);
+  }
+
+  static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+  let runtime = RUNTIME.get_or_init(|| {
+    Builder::new_multi_thread()
+      .worker_threads(ISOLATED_WORKERS)
+      .thread_name("millama-llm")
+      .enable_all()
+      .build()
+      .expect("Failed to build LLM runtime")
+  });
+
+  runtime.spawn(fut).await.context("LLM task panicked")
+}
+
+/// Sends completion requests through one client, so fallbacks, retries and
+/// later drafts reuse its pooled keep-alive connections.
+#[derive(Debug, Clone, Default)]
+pub struct LlmClient {
+  http: reqwest::Client,
+}
+
+impl LlmClient {
+  pub fn new(http: reqwest::Client) -> Self {
+    Self { http }
+  }
+
+  /// The client behind the free functions below.
+  fn shared() -> &'static LlmClient {
+    static SHARED: OnceLock<LlmClient> = OnceLock::new();
+    SHARED.get_or_init(LlmClient::default)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub async fn generate_reply(
+    &self,
+    api_key: &str,
+    api_url: &str,
+    model: &str,
+    temperature: f32,
+    system_prompt: &str,
+    history: Vec<ChatMessage>,
+    options: RequestOptions<'_>,
+  ) -> Result<String> {
+    let (reply, _) = self
+      .generate_reply_with_model(
+        api_key,
+        api_url,
+        model,
+        temperature,
+        system_prompt,
+        history,
+        options,
+      )
+      .await?;
+    Ok(reply.text)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub async fn generate_reply_with_fallback(
+    &self,
+    api_key: &str,
+    api_url: &str,
+    models: Vec<String>,
+    temperature: f32,
+    system_prompt: &str,
+    history: Vec<ChatMessage>,
+    options: RequestOptions<'_>,
+  ) -> Result<Completion> {
+    if models.is_empty() {
+      return Err(anyhow!("No models configured"));
+    }
+
+    let mut last_error = None;
+
+    for (idx, model) in models.iter().enumerate() {
+      debug!("Trying model {} of {}: {}", idx + 1, models.len(), model);
+
+      let mut attempt = 0;
+      loop {
+        match self
+          .generate_reply_with_model(
+            api_key,
+            api_url,
+            model,
+            temperature,
+            system_prompt,
+            history.clone(),
+            options,
+          )
+          .await
+        {
+          Ok((reply, request_id)) => {
+            if idx > 0 {
+              debug!(
+                "Successfully generated reply with fallback model: {}",
+                model
+              );
+            }
+            return Ok(Completion {
+              text: reply.text,
+              model: model.clone(),
+              request_id,
+              tokens: reply.tokens,
+            });
+          }
+          Err(e)
+            if e.is::<EmptyChoices>()
+              && attempt < options.empty_choices_retries =>
+          {
+            attempt += 1;
+            warn!("Model {} returned no choices, retrying", model);
+          }
+          Err(e) => {
+            warn!("Model {} failed: {}", model, e);
+            last_error = Some(e);
+            break;
+          }
+        }
+      }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow!("All models failed")))
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  async fn generate_reply_with_model(
+    &self,
+    api_key: &str,
+    api_url: &str,
+    model: &str,
+    temperature: f32,
+    system_prompt: &str,
+    history: Vec<ChatMessage>,
+    options: RequestOptions<'_>,
+  ) -> Result<(Reply, Option<String>)> {
+    debug!("Generating reply with model: {}", model);
+    trace!("System prompt: {}", system_prompt);
+    trace!("History length: {}", history.len());
+
+    let request_id =
+      options.request_id_header.map(|_| Uuid::new_v4().to_string());
+    let call = Call {
+      api_key,
+      api_url,
+      model,
+      temperature,
+      system_prompt,
+      history,
+      options,
+      request_id: request_id.as_deref(),
+    };
+
+    let semaphore = options.limits.and_then(|limits| limits.semaphore(model));
+    let _permit = match &semaphore {
+      Some(semaphore) => Some(semaphore.acquire().await?),
+      None => None,
+    };
+    let mut reply = match options.provider {
+      Provider::OpenAi => OpenAi.generate(&self.http, call).await?,
+      Provider::Anthropic => Anthropic.generate(&self.http, call).await?,
+      Provider::Ollama => Ollama.generate(&self.http, call).await?,
+    };
+    reply.text = strip_reasoning(&reply.text).to_string();
+    Ok((reply, request_id))
+  }
+}
+
+/// One completion request to one model.
+struct Call<'a> {
+  api_key: &'a str,
+  api_url: &'a str,
+  model: &'a str,
+  temperature: f32,
+  system_prompt: &'a str,
+  history: Vec<ChatMessage>,
+  options: RequestOptions<'a>,
+  request_id: Option<&'a str>,
+}
+
+/// A completion API wire format.
+trait LlmProvider {
+  /// Sends `call` and returns the reply, streaming it if asked to.
+  async fn generate(
+    &self,
+    http: &reqwest::Client,
+    call: Call<'_>,
+  ) -> Result<Reply>;
+}
+
+/// The OpenAI chat completions API, spoken by most providers.
+struct OpenAi;
+
+impl OpenAi {
+  fn payload(call: &Call<'_>) -> CompletionRequest {
+    let mut messages = vec![ChatMessage {
+      role: "system".into(),
+      content: call.system_prompt.into(),
+    }];
+    messages.extend(call.history.iter().cloned());
+
+    let options = &call.options;
+    CompletionRequest {
+      model: call.model.to_string(),
+      messages,
+      temperature: call.temperature,
+      user: options.user.map(str::to_string),
+      frequency_penalty: options.frequency_penalty,
+      presence_penalty: options.presence_penalty,
+      max_tokens: options.max_tokens,
+      top_p: options.top_p,
+      stream: options.stream.is_some(),
+      stream_options: options
+        .stream
+        .map(|_| StreamOptions { include_usage: true }),
+    }
+  }
+}
+
+impl LlmProvider for OpenAi {
+  async fn generate(
+    &self,
+    http: &reqwest::Client,
+    call: Call<'_>,
+  ) -> Result<Reply> {
+    let options = &call.options;
+    let auth = options.auth.unwrap_or(&AuthMode::Bearer);
+    let request = auth
+      .apply(http.post(call.api_url), call.api_key)
+      .json(&Self::payload(&call));
+    let response = send(request, &call).await?;
+
+    if let Some(partial) = options.stream {
+      let reply = read_stream(response, partial).await?;
+      debug!("Successfully streamed reply");
+      return Ok(reply);
+    }
+
+    let resp_json = response.json::<CompletionResponse>().await?;
+    let tokens = resp_json.usage.map_or(0, |usage| usage.total_tokens);
+
+    if let Some(choice) = resp_json.choices.first() {
+      debug!("Successfully generated reply");
+      if let Some(reasoning) = &choice.message.reasoning_content {
+        trace!("Reasoning content: {}", reasoning);
+      }
+      trace!("Reply content: {}", choice.message.content);
+      Ok(Reply { text: choice.message.content.clone(), tokens })
+    } else {
+      Err(EmptyChoices.into())
+    }
+  }
+}
+
+/// Anthropic's messages API, which takes the system prompt apart from the
+/// conversation and doesn't know the sampling penalties.
+struct Anthropic;
+
+impl Anthropic {
+  fn payload<'a>(call: &'a Call<'_>) -> MessagesRequest<'a> {
+    MessagesRequest {
+      model: call.model,
+      // Required by Anthropic, unlike OpenAI
+      max_tokens: call.options.max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS),
+      system: call.system_prompt,
+      messages: &call.history,
+      // Anthropic only accepts up to 1.0
+      temperature: call.temperature.clamp(0.0, 1.0),
+      top_p: call.options.top_p,
+      metadata: call.options.user.map(|user_id| Metadata { user_id }),
+      stream: call.options.stream.is_some(),
+    }
+  }
+}
+
+impl LlmProvider for Anthropic {
+  async fn generate(
+    &self,
+    http: &reqwest::Client,
+    call: Call<'_>,
+  ) -> Result<Reply> {
+    let request = match call.options.auth {
+      Some(auth) => auth.apply(http.post(call.api_url), call.api_key),
+      None => http.post(call.api_url).header("x-api-key", call.api_key),
+    };
+    let request = request
+      .header("anthropic-version", ANTHROPIC_VERSION)
+      .json(&Self::payload(&call));
+    let response = send(request, &call).await?;
+
+    if let Some(partial) = call.options.stream {
+      let reply = read_messages_stream(response, partial).await?;
+      debug!("Successfully streamed reply");
+      return Ok(reply);
+    }
+
+    let resp_json = response.json::<MessagesResponse>().await?;
+    let text: String =
+      resp_json.content.into_iter().filter_map(|block| block.text).collect();
+    if text.is_empty() {
+      return Err(EmptyChoices.into());
+    }
+    debug!("Successfully generated reply");
+    trace!("Reply content: {}", text);
+    let usage = resp_json.usage;
+    Ok(Reply { text, tokens: usage.input_tokens + usage.output_tokens })
+  }
+}
+
+/// Ollama's native chat API, which answers in one object and needs no key.
+struct Ollama;
+
+impl Ollama {
+  fn payload<'a>(call: &'a Call<'_>) -> OllamaRequest<'a> {
+    let mut messages = vec![ChatMessage {
+      role: "system".into(),
+      content: call.system_prompt.into(),
+    }];
+    messages.extend(call.history.iter().cloned());
+
+    let options = &call.options;
+    OllamaRequest {
+      model: call.model,
+      messages,
+      // The reply is short enough to wait for in one piece
+      stream: false,
+      options: OllamaOptions {
+        temperature: call.temperature,
+        top_p: options.top_p,
+        frequency_penalty: options.frequency_penalty,
+        presence_penalty: options.presence_penalty,
+        num_predict: options.max_tokens,
+      },
+    }
+  }
+}
+
+impl LlmProvider for Ollama {
+  async fn generate(
+    &self,
+    http: &reqwest::Client,
+    call: Call<'_>,
+  ) -> Result<Reply> {
+    // A local server has no key, but one behind a proxy may want it
+    let request = match call.options.auth {
+      Some(auth) => auth.apply(http.post(call.api_url), call.api_key),
+      None => http.post(call.api_url),
+    };
+    let response = send(request.json(&Self::payload(&call)), &call).await?;
+
+    let resp_json = response.json::<OllamaResponse>().await?;
+    let text = resp_json.message.content;
+    if text.is_empty() {
+      return Err(EmptyChoices.into());
+    }
+    if let Some(partial) = call.options.stream {
+      send_preview(partial, &text);
+    }
+    debug!("Successfully generated reply");
+    trace!("Reply content: {}", text);
+    let tokens = resp_json.prompt_eval_count + resp_json.eval_count;
+    Ok(Reply { text, tokens })
+  }
+}
+
+/// Sends `request` with the extras of `call`, turning error statuses into
+/// errors.
+async fn send(
+  mut request: reqwest::RequestBuilder,
+  call: &Call<'_>,
+) -> Result<reqwest::Response> {
+  if let (Some(header), Some(id)) =
+    (call.options.request_id_header, call.request_id)
+  {
+    request = request.header(header, id);
+  }
+  if let Some(timeout) = call.options.timeout {
+    request = request.timeout(timeout);
+  }
+
+  debug!(
+    "Sending request to {:?} API (request id {:?})",
+    call.options.provider, call.request_id
+  );
+  // The URL may carry the key with `AuthMode::Query`, keep it out of errors
+  let response = request
+    .send()
+    .await
+    .map_err(reqwest::Error::without_url)
+    .with_context(|| format!("Failed to reach {}", call.api_url))?;
+
+  let status = response.status();
+
+  if !status.is_success() {
+    let error_text = response.text().await?;
+
+    // Check for rate limiting (429) specifically
+    if status.as_u16() == 429 {
+      warn!("Rate limit (429) reached for model: {}", call.model);
+      return Err(anyhow!("Rate limit (429): {}", error_text));
+    }
+
+    return Err(anyhow!("API Error {}: {}", status, error_text));
+  }
+  Ok(response)
 }
 
 #[allow(dead_code)]
@@ -40,16 +809,19 @@ pub async fn generate_reply(
   temperature: f32,
   system_prompt: &str,
   history: Vec<ChatMessage>,
+  options: RequestOptions<'_>,
 ) -> Result<String> {
-  generate_reply_with_model(
-    api_key,
-    api_url,
-    model,
-    temperature,
-    system_prompt,
-    history,
-  )
-  .await
+  LlmClient::shared()
+    .generate_reply(
+      api_key,
+      api_url,
+      model,
+      temperature,
+      system_prompt,
+      history,
+      options,
+    )
+    .await
 }
 
 pub async fn generate_reply_with_fallback(
@@ -59,92 +831,735 @@ pub async fn generate_reply_with_fallback(
   temperature: f32,
   system_prompt: &str,
   history: Vec<ChatMessage>,
-) -> Result<String> {
-  if models.is_empty() {
-    return Err(anyhow!("No models configured"));
-  }
-
-  let mut last_error = None;
-
-  for (idx, model) in models.iter().enumerate() {
-    debug!("Trying model {} of {}: {}", idx + 1, models.len(), model);
-
-    match generate_reply_with_model(
+  options: RequestOptions<'_>,
+) -> Result<Completion> {
+  LlmClient::shared()
+    .generate_reply_with_fallback(
       api_key,
       api_url,
-      model,
+      models,
       temperature,
       system_prompt,
-      history.clone(),
+      history,
+      options,
     )
     .await
-    {
-      Ok(response) => {
-        if idx > 0 {
-          debug!("Successfully generated reply with fallback model: {}", model);
-        }
-        return Ok(response);
-      }
-      Err(e) => {
-        warn!("Model {} failed: {}", model, e);
-        last_error = Some(e);
+}
+
+/// Feeds the `data:` payloads of a server-sent event stream to `on_data`
+/// until it returns `false` or the stream ends.
+async fn for_each_event(
+  mut response: reqwest::Response,
+  mut on_data: impl FnMut(&str) -> Result<bool>,
+) -> Result<()> {
+  let mut buf = Vec::new();
+  while let Some(chunk) = response.chunk().await? {
+    buf.extend_from_slice(&chunk);
+    while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+      let line: Vec<u8> = buf.drain(..=end).collect();
+      let line = String::from_utf8_lossy(&line);
+      let Some(data) = line.trim().strip_prefix("data:") else {
+        continue;
+      };
+      if !on_data(data.trim())? {
+        return Ok(());
       }
     }
   }
+  Ok(())
+}
+
+/// Collects the deltas of a chat completions stream into the full reply.
+async fn read_stream(
+  response: reqwest::Response,
+  partial: &UnboundedSender<String>,
+) -> Result<Reply> {
+  let mut text = String::new();
+  let mut tokens = 0;
+  let mut any_choice = false;
+
+  for_each_event(response, |data| {
+    if data == "[DONE]" {
+      return Ok(false);
+    }
+
+    let chunk: StreamChunk = json::from_str(data)
+      .with_context(|| format!("Invalid stream chunk: {}", data))?;
+    if let Some(usage) = chunk.usage {
+      tokens = usage.total_tokens;
+    }
+    let Some(choice) = chunk.choices.into_iter().next() else {
+      return Ok(true);
+    };
+    any_choice = true;
+    if let Some(content) = choice.delta.content.filter(|c| !c.is_empty()) {
+      text.push_str(&content);
+      send_preview(partial, &text);
+    }
+    Ok(true)
+  })
+  .await?;
+
+  if !any_choice {
+    return Err(EmptyChoices.into());
+  }
+  trace!("Reply content: {}", text);
+  Ok(Reply { text, tokens })
+}
+
+/// Collects the text deltas of an Anthropic messages stream into the reply.
+async fn read_messages_stream(
+  response: reqwest::Response,
+  partial: &UnboundedSender<String>,
+) -> Result<Reply> {
+  let mut text = String::new();
+  let (mut input_tokens, mut output_tokens) = (0, 0);
+
+  for_each_event(response, |data| {
+    let event: MessagesEvent = json::from_str(data)
+      .with_context(|| format!("Invalid stream event: {}", data))?;
+    if let Some(message) = &event.message {
+      input_tokens = message.usage.input_tokens;
+    }
+    if let Some(usage) = &event.usage {
+      output_tokens = usage.output_tokens;
+    }
+    match event.kind.as_str() {
+      "message_stop" => return Ok(false),
+      "error" => return Err(anyhow!("Stream error: {}", data)),
+      _ => {}
+    }
+    if let Some(delta) = event.delta.and_then(|delta| delta.text)
+      && !delta.is_empty()
+    {
+      text.push_str(&delta);
+      send_preview(partial, &text);
+    }
+    Ok(true)
+  })
+  .await?;
 
-  Err(last_error.unwrap_or_else(|| anyhow!("All models failed")))
+  if text.is_empty() {
+    return Err(EmptyChoices.into());
+  }
+  trace!("Reply content: {}", text);
+  Ok(Reply { text, tokens: input_tokens + output_tokens })
 }
 
-async fn generate_reply_with_model(
-  api_key: &str,
-  api_url: &str,
-  model: &str,
-  temperature: f32,
-  system_prompt: &str,
-  history: Vec<ChatMessage>,
-) -> Result<String> {
-  debug!("Generating reply with model: {}", model);
-  trace!("System prompt: {}", system_prompt);
-  trace!("History length: {}", history.len());
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::testing::{MockServer, Response},
+  };
 
-  let client = reqwest::Client::new();
+  const COMPLETION: &str =
+    r#"{"choices":[{"message":{"role":"assistant","content":"hello"}}]}"#;
 
-  let mut messages =
-    vec![ChatMessage { role: "system".into(), content: system_prompt.into() }];
-  messages.extend(history);
+  #[tokio::test]
+  async fn test_fallback_reports_succeeding_model() {
+    let server = MockServer::start(vec![
+      Response::json(429, r#"{"error":"rate limited"}"#),
+      Response::json(200, COMPLETION),
+    ])
+    .await;
 
-  let payload =
-    CompletionRequest { model: model.to_string(), messages, temperature };
+    let completion = generate_reply_with_fallback(
+      "key",
+      &server.url("/v1/chat/completions"),
+      vec!["primary".to_string(), "backup".to_string()],
+      1.0,
+      "system",
+      vec![],
+      RequestOptions::default(),
+    )
+    .await
+    .unwrap();
 
-  debug!("Sending request to OpenAI-compatible API");
-  let response = client
-    .post(api_url)
-    .header("Authorization", format!("Bearer {}", api_key))
-    .json(&payload)
-    .send()
-    .await?;
+    assert_eq!(completion.text, "hello");
+    assert_eq!(completion.model, "backup");
 
-  let status = response.status();
+    let tried: Vec<_> =
+      server.requests().iter().map(|r| r.json()["model"].clone()).collect();
+    assert_eq!(tried, ["primary", "backup"]);
+  }
 
-  if !status.is_success() {
-    let error_text = response.text().await?;
+  #[test]
+  fn test_strip_reasoning() {
+    let reply = "<think>\nThey asked about dinner.\n</think>\n\nSure, 8 works!";
+    assert_eq!(strip_reasoning(reply), "Sure, 8 works!");
+    assert_eq!(strip_reasoning("  <think></think>Hi"), "Hi");
+    assert_eq!(strip_reasoning("Sure, 8 works!"), "Sure, 8 works!");
+    // Only a leading block is reasoning, and an unclosed one is kept
+    let quoted = "I said <think>twice</think> before";
+    assert_eq!(strip_reasoning(quoted), quoted);
+    assert_eq!(strip_reasoning("<think>still going"), "<think>still going");
+  }
 
-    // Check for rate limiting (429) specifically
-    if status.as_u16() == 429 {
-      warn!("Rate limit (429) reached for model: {}", model);
-      return Err(anyhow!("Rate limit (429): {}", error_text));
+  #[test]
+  fn test_reasoning_is_not_previewed() {
+    assert_eq!(reasoning_preview("<th"), None);
+    assert_eq!(reasoning_preview(" <think>Let me see"), None);
+    assert_eq!(reasoning_preview("<think>Hmm</think>\nSure"), Some("Sure"));
+    assert_eq!(reasoning_preview("Sure, 8"), Some("Sure, 8"));
+    assert_eq!(reasoning_preview("I <think> so"), Some("I <think> so"));
+  }
+
+  #[tokio::test]
+  async fn test_reasoning_is_left_out_of_the_reply() {
+    let server = MockServer::start(vec![Response::json(
+      200,
+      r#"{"choices":[{"message":{"content":"<think>hmm</think> hello",
+        "reasoning_content":"They greeted me."}}]}"#,
+    )])
+    .await;
+
+    let completion = generate_reply_with_fallback(
+      "key",
+      &server.url("/v1/chat/completions"),
+      vec!["reasoner".to_string()],
+      1.0,
+      "system",
+      vec![],
+      RequestOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(completion.text, "hello");
+  }
+
+  #[tokio::test]
+  async fn test_timed_out_model_falls_back() {
+    let server = MockServer::start(vec![
+      Response::json(200, COMPLETION).delayed(Duration::from_secs(5)),
+      Response::json(200, COMPLETION),
+    ])
+    .await;
+
+    let options = RequestOptions {
+      timeout: Some(Duration::from_millis(200)),
+      ..Default::default()
+    };
+    let completion = generate_reply_with_fallback(
+      "key",
+      &server.url("/v1/chat/completions"),
+      vec!["hanging".to_string(), "backup".to_string()],
+      1.0,
+      "system",
+      vec![],
+      options,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(completion.model, "backup");
+    assert_eq!(server.requests().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_empty_choices_are_retried() {
+    let server = MockServer::start(vec![
+      Response::json(200, r#"{"choices":[]}"#),
+      Response::json(200, COMPLETION),
+      Response::json(200, r#"{"choices":[]}"#),
+      Response::json(200, COMPLETION),
+    ])
+    .await;
+    let url = server.url("/v1/chat/completions");
+    let models = vec!["primary".to_string(), "backup".to_string()];
+
+    let options =
+      RequestOptions { empty_choices_retries: 1, ..Default::default() };
+    let completion = generate_reply_with_fallback(
+      "key",
+      &url,
+      models.clone(),
+      1.0,
+      "system",
+      vec![],
+      options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(completion.text, "hello");
+    assert_eq!(completion.model, "primary");
+
+    // Without retries the fallback model takes over instead
+    let completion = generate_reply_with_fallback(
+      "key",
+      &url,
+      models,
+      1.0,
+      "system",
+      vec![],
+      RequestOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(completion.model, "backup");
+  }
+
+  #[tokio::test]
+  async fn test_streamed_reply_reports_partials() {
+    let body = concat!(
+      "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+      "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+      "data: {\"choices\":[{\"delta\":{\"content\":\"lo!\"}}]}\n\n",
+      "data: [DONE]\n\n",
+    );
+    let server = MockServer::start(vec![Response::json(200, body)]).await;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let completion = generate_reply_with_fallback(
+      "key",
+      &server.url("/v1/chat/completions"),
+      vec!["primary".into()],
+      1.0,
+      "system",
+      Vec::new(),
+      RequestOptions { stream: Some(&tx), ..Default::default() },
+    )
+    .await
+    .unwrap();
+    drop(tx);
+
+    assert_eq!(completion.text, "Hello!");
+    assert_eq!(server.requests()[0].json()["stream"], true);
+    let mut partials = Vec::new();
+    while let Some(text) = rx.recv().await {
+      partials.push(text);
     }
+    assert_eq!(partials, ["Hel", "Hello!"]);
+  }
 
-    return Err(anyhow!("API Error {}: {}", status, error_text));
+  #[test]
+  fn test_openai_request_serialization() {
+    let call = Call {
+      api_key: "key",
+      api_url: "http://localhost",
+      model: "model",
+      temperature: 0.5,
+      system_prompt: "Be brief",
+      history: vec![ChatMessage { role: "user".into(), content: "hi".into() }],
+      options: RequestOptions::default(),
+      request_id: None,
+    };
+
+    let payload = json::to_value(OpenAi::payload(&call)).unwrap();
+    assert_eq!(
+      payload,
+      json::json!({
+        "model": "model",
+        "messages": [
+          {"role": "system", "content": "Be brief"},
+          {"role": "user", "content": "hi"},
+        ],
+        "temperature": 0.5,
+      })
+    );
+
+    let capped = Call {
+      options: RequestOptions {
+        max_tokens: Some(200),
+        top_p: Some(0.5),
+        ..Default::default()
+      },
+      ..call
+    };
+    let payload = json::to_value(OpenAi::payload(&capped)).unwrap();
+    assert_eq!(payload["max_tokens"], 200);
+    assert_eq!(payload["top_p"], 0.5);
   }
 
-  let resp_json = response.json::<CompletionResponse>().await?;
+  #[test]
+  fn test_anthropic_request_serialization() {
+    let history = vec![
+      ChatMessage { role: "user".into(), content: "hi".into() },
+      ChatMessage { role: "assistant".into(), content: "hello".into() },
+    ];
+    let call = Call {
+      api_key: "key",
+      api_url: "http://localhost",
+      model: "claude-sonnet-4-5",
+      temperature: 1.5,
+      system_prompt: "Be brief",
+      history,
+      options: RequestOptions {
+        user: Some("millama"),
+        frequency_penalty: Some(0.5),
+        ..Default::default()
+      },
+      request_id: None,
+    };
 
-  if let Some(choice) = resp_json.choices.first() {
-    debug!("Successfully generated reply");
-    trace!("Reply content: {}", choice.message.content);
-    Ok(choice.message.content.clone())
-  } else {
-    Err(anyhow!("No choices in response"))
+    let payload = json::to_value(Anthropic::payload(&call)).unwrap();
+    assert_eq!(
+      payload,
+      json::json!({
+        "model": "claude-sonnet-4-5",
+        "max_tokens": ANTHROPIC_MAX_TOKENS,
+        "system": "Be brief",
+        "messages": [
+          {"role": "user", "content": "hi"},
+          {"role": "assistant", "content": "hello"},
+        ],
+        "temperature": 1.0,
+        "metadata": {"user_id": "millama"},
+      })
+    );
+
+    let bare =
+      Call { system_prompt: "", options: RequestOptions::default(), ..call };
+    let payload = json::to_value(Anthropic::payload(&bare)).unwrap();
+    assert!(payload.get("system").is_none());
+    assert!(payload.get("metadata").is_none());
+  }
+
+  #[tokio::test]
+  async fn test_auth_modes_place_the_key() {
+    let server = MockServer::start(vec![Response::json(200, COMPLETION)]).await;
+    let url = server.url("/v1/chat/completions");
+    let modes = [
+      None,
+      Some(AuthMode::Bearer),
+      Some(AuthMode::Header { name: "x-goog-api-key".into() }),
+      Some(AuthMode::Query { param: "key".into() }),
+    ];
+    for auth in &modes {
+      let options =
+        RequestOptions { auth: auth.as_ref(), ..Default::default() };
+      generate_reply("secret", &url, "gemini", 1.0, "system", vec![], options)
+        .await
+        .unwrap();
+    }
+
+    let requests = server.requests();
+    for request in &requests[..2] {
+      assert_eq!(request.header("authorization"), Some("Bearer secret"));
+      assert_eq!(request.target, "/v1/chat/completions");
+    }
+    assert_eq!(requests[2].header("x-goog-api-key"), Some("secret"));
+    assert_eq!(requests[2].header("authorization"), None);
+    assert_eq!(requests[3].target, "/v1/chat/completions?key=secret");
+    assert_eq!(requests[3].header("authorization"), None);
+  }
+
+  #[tokio::test]
+  async fn test_query_auth_errors_hide_the_key() {
+    // Nothing listens on the port once the listener is dropped
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/v1", listener.local_addr().unwrap());
+    drop(listener);
+
+    let auth = AuthMode::Query { param: "key".into() };
+    let options = RequestOptions { auth: Some(&auth), ..Default::default() };
+    let error =
+      generate_reply("secret", &url, "gemini", 1.0, "system", vec![], options)
+        .await
+        .unwrap_err();
+    let error = format!("{:#}", error);
+    assert!(error.contains("Failed to reach"), "{}", error);
+    assert!(!error.contains("secret"), "{}", error);
+  }
+
+  #[tokio::test]
+  async fn test_anthropic_provider_headers_and_reply() {
+    let reply = r#"{"content":[{"type":"text","text":"hello"}]}"#;
+    let server = MockServer::start(vec![Response::json(200, reply)]).await;
+
+    let options =
+      RequestOptions { provider: Provider::Anthropic, ..Default::default() };
+    let completion = generate_reply_with_fallback(
+      "secret",
+      &server.url("/v1/messages"),
+      vec!["claude".into()],
+      0.7,
+      "system",
+      vec![],
+      options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(completion.text, "hello");
+
+    let request = &server.requests()[0];
+    assert_eq!(request.header("x-api-key"), Some("secret"));
+    assert_eq!(request.header("anthropic-version"), Some(ANTHROPIC_VERSION));
+    assert_eq!(request.header("authorization"), None);
+    assert_eq!(request.json()["system"], "system");
+  }
+
+  #[tokio::test]
+  async fn test_ollama_provider_round_trip() {
+    let reply = r#"{"model":"llama3","created_at":"2024-05-01T12:00:00Z",
+      "message":{"role":"assistant","content":"hey there"},"done":true,
+      "prompt_eval_count":26,"eval_count":4}"#;
+    let server = MockServer::start(vec![Response::json(200, reply)]).await;
+
+    let options = RequestOptions {
+      provider: Provider::Ollama,
+      max_tokens: Some(64),
+      ..Default::default()
+    };
+    let completion = generate_reply_with_fallback(
+      "",
+      &server.url("/api/chat"),
+      vec!["llama3".into()],
+      0.5,
+      "system",
+      vec![ChatMessage { role: "user".into(), content: "hi".into() }],
+      options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(completion.text, "hey there");
+    assert_eq!(completion.tokens, 30);
+
+    let request = &server.requests()[0];
+    assert_eq!(request.header("authorization"), None);
+    let body = request.json();
+    assert_eq!(body["model"], "llama3");
+    assert_eq!(body["stream"], false);
+    assert_eq!(body["options"]["temperature"], 0.5);
+    assert_eq!(body["options"]["num_predict"], 64);
+    assert_eq!(body["messages"][0]["role"], "system");
+    assert_eq!(body["messages"][1]["content"], "hi");
+  }
+
+  #[tokio::test]
+  async fn test_anthropic_stream_reports_partials() {
+    let body = concat!(
+      "event: message_start\n",
+      "data: {\"type\":\"message_start\",\"message\":{}}\n\n",
+      "data: {\"type\":\"content_block_delta\",",
+      "\"delta\":{\"text\":\"Hel\"}}\n\n",
+      "data: {\"type\":\"content_block_delta\",",
+      "\"delta\":{\"text\":\"lo!\"}}\n\n",
+      "data: {\"type\":\"message_stop\"}\n\n",
+    );
+    let server = MockServer::start(vec![Response::json(200, body)]).await;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let options = RequestOptions {
+      stream: Some(&tx),
+      provider: Provider::Anthropic,
+      ..Default::default()
+    };
+    let completion = generate_reply_with_fallback(
+      "key",
+      &server.url("/v1/messages"),
+      vec!["claude".into()],
+      1.0,
+      "system",
+      Vec::new(),
+      options,
+    )
+    .await
+    .unwrap();
+    drop(tx);
+
+    assert_eq!(completion.text, "Hello!");
+    let mut partials = Vec::new();
+    while let Some(text) = rx.recv().await {
+      partials.push(text);
+    }
+    assert_eq!(partials, ["Hel", "Hello!"]);
+  }
+
+  #[tokio::test]
+  async fn test_token_usage_is_reported() {
+    let reply = concat!(
+      r#"{"choices":[{"message":{"content":"hi"}}],"#,
+      r#""usage":{"total_tokens":42}}"#,
+    );
+    let stream = concat!(
+      "data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n\n",
+      "data: {\"choices\":[],\"usage\":{\"total_tokens\":7}}\n\n",
+      "data: [DONE]\n\n",
+    );
+    let server = MockServer::start(vec![
+      Response::json(200, reply),
+      Response::json(200, stream),
+    ])
+    .await;
+    let url = server.url("/v1/chat/completions");
+    let models = vec!["model".to_string()];
+
+    let completion = generate_reply_with_fallback(
+      "key",
+      &url,
+      models.clone(),
+      1.0,
+      "system",
+      vec![],
+      RequestOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(completion.tokens, 42);
+
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let options = RequestOptions { stream: Some(&tx), ..Default::default() };
+    let completion = generate_reply_with_fallback(
+      "key",
+      &url,
+      models,
+      1.0,
+      "system",
+      vec![],
+      options,
+    )
+    .await
+    .unwrap();
+    assert_eq!(completion.tokens, 7);
+    assert_eq!(
+      server.requests()[1].json()["stream_options"]["include_usage"],
+      true
+    );
+  }
+
+  #[tokio::test]
+  async fn test_user_tag_is_sent() {
+    let server = MockServer::start(vec![Response::json(200, COMPLETION)]).await;
+    let url = server.url("/v1/chat/completions");
+
+    let tagged = RequestOptions { user: Some("millama"), ..Default::default() };
+    generate_reply("key", &url, "model", 1.0, "system", vec![], tagged)
+      .await
+      .unwrap();
+    let untagged = RequestOptions::default();
+    generate_reply("key", &url, "model", 1.0, "system", vec![], untagged)
+      .await
+      .unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests[0].json()["user"], "millama");
+    assert!(requests[1].json().get("user").is_none());
+  }
+
+  #[tokio::test]
+  async fn test_request_id_header_matches_completion() {
+    let server = MockServer::start(vec![Response::json(200, COMPLETION)]).await;
+
+    let options = RequestOptions {
+      request_id_header: Some("X-Request-Id"),
+      ..Default::default()
+    };
+    let completion = generate_reply_with_fallback(
+      "key",
+      &server.url("/v1/chat/completions"),
+      vec!["model".to_string()],
+      1.0,
+      "system",
+      vec![],
+      options,
+    )
+    .await
+    .unwrap();
+
+    let request_id = completion.request_id.unwrap();
+    assert_eq!(request_id.len(), 36);
+    assert_eq!(
+      server.requests()[0].header("x-request-id"),
+      Some(request_id.as_str())
+    );
+  }
+
+  #[tokio::test]
+  async fn test_refinement_targets_critic_model() {
+    let server = MockServer::start(vec![
+      Response::json(200, COMPLETION),
+      Response::json(500, r#"{"error":"critic down"}"#),
+      Response::json(200, COMPLETION),
+    ])
+    .await;
+    let url = server.url("/v1/chat/completions");
+    let options = RequestOptions::default();
+
+    let draft = generate_reply_with_fallback(
+      "key",
+      &url,
+      vec!["primary".to_string()],
+      1.0,
+      "system",
+      vec![],
+      options,
+    )
+    .await
+    .unwrap();
+
+    let models = refine_models(Some("critic"), &draft.model);
+    assert_eq!(models, ["critic", "primary"]);
+    let refined = generate_reply_with_fallback(
+      "key",
+      &url,
+      models,
+      1.0,
+      "system",
+      refine_messages(vec![], &draft.text),
+      options,
+    )
+    .await
+    .unwrap();
+
+    // The critic failing falls back to the primary
+    assert_eq!(refined.model, "primary");
+    let requests = server.requests();
+    let tried: Vec<_> =
+      requests.iter().map(|r| r.json()["model"].clone()).collect();
+    assert_eq!(tried, ["primary", "critic", "primary"]);
+    let review = requests[1].json()["messages"][1]["content"].clone();
+    assert!(review.as_str().unwrap().contains("hello"));
+
+    assert_eq!(refine_models(None, "primary"), ["primary"]);
+  }
+
+  #[tokio::test]
+  async fn test_max_concurrency_serializes_per_model() {
+    use std::time::{Duration, Instant};
+
+    let delay = Duration::from_millis(200);
+    let server =
+      MockServer::start(vec![Response::json(200, COMPLETION).delayed(delay)])
+        .await;
+    let url = server.url("/v1/chat/completions");
+    let limits = ModelLimits::new(HashMap::from([("strict".to_string(), 1)]));
+
+    let started = Instant::now();
+    let request = |model: &'static str| {
+      let (url, limits) = (&url, &limits);
+      async move {
+        let options =
+          RequestOptions { limits: Some(limits), ..Default::default() };
+        generate_reply("key", url, model, 1.0, "system", vec![], options)
+          .await
+          .unwrap();
+        started.elapsed()
+      }
+    };
+    let (first, second, local) =
+      tokio::join!(request("strict"), request("strict"), request("local"));
+
+    // The strict model's second request waited for the first one
+    assert!(first.max(second) >= delay * 2);
+    assert!(local < delay * 2);
+  }
+
+  #[tokio::test(flavor = "current_thread")]
+  async fn test_isolated_generation_keeps_updates_responsive() {
+    use std::time::{Duration, Instant};
+
+    // A generation that blocks its thread, like a heavy local model call
+    let generation = tokio::spawn(run_isolated(true, async {
+      std::thread::sleep(Duration::from_millis(500));
+      "reply"
+    }));
+
+    let started = Instant::now();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(started.elapsed() < Duration::from_millis(250));
+
+    assert_eq!(generation.await.unwrap().unwrap(), "reply");
   }
 }
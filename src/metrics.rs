@@ -0,0 +1,195 @@
+//! Counters served over `metrics_addr` in the Prometheus text format, with a
+//! `/healthz` for the service manager.
+
+use {
+  std::{
+    fmt::Write as _,
+    sync::{
+      Arc,
+      atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+  },
+  tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+  },
+  tracing::debug,
+};
+
+/// How long after the last fetch of bot updates `/healthz` stops answering
+/// 200, well past the 30 second long poll and the retries of a failed one.
+const POLL_STALE_SECS: u64 = 120;
+
+/// What is counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+  /// A draft card was offered.
+  Generated,
+  Approved,
+  Rejected,
+  /// Every model in the fallback chain failed.
+  LlmFailure,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+  generated: AtomicU64,
+  approved: AtomicU64,
+  rejected: AtomicU64,
+  llm_failures: AtomicU64,
+  alive: AtomicBool,
+  /// Unix time of the last successful fetch of bot updates.
+  last_poll: AtomicU64,
+}
+
+impl Metrics {
+  pub fn count(&self, counter: Counter) {
+    let counter = match counter {
+      Counter::Generated => &self.generated,
+      Counter::Approved => &self.approved,
+      Counter::Rejected => &self.rejected,
+      Counter::LlmFailure => &self.llm_failures,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Marks the update loop as running or stopped for `/healthz`.
+  pub fn set_alive(&self, alive: bool) {
+    self.alive.store(alive, Ordering::Relaxed);
+  }
+
+  /// Notes that bot updates were just fetched, which keeps `/healthz` up.
+  pub fn polled(&self) {
+    self.last_poll.store(unix_now(), Ordering::Relaxed);
+  }
+
+  /// Whether the update loop runs and bot updates were fetched lately.
+  fn healthy(&self, now: u64) -> bool {
+    let last_poll = self.last_poll.load(Ordering::Relaxed);
+    self.alive.load(Ordering::Relaxed)
+      && now.saturating_sub(last_poll) <= POLL_STALE_SECS
+  }
+
+  /// The counters and `pending` drafts in the Prometheus text format.
+  pub fn render(&self, pending: usize) -> String {
+    let counters = [
+      ("drafts_generated", "Draft cards offered", &self.generated),
+      ("drafts_approved", "Drafts approved and sent", &self.approved),
+      ("drafts_rejected", "Drafts rejected", &self.rejected),
+      ("llm_failures", "Drafts no model could write", &self.llm_failures),
+    ];
+    let mut text = String::new();
+    for (name, help, value) in counters {
+      let value = value.load(Ordering::Relaxed);
+      let _ = writeln!(text, "# HELP millama_{}_total {}.", name, help);
+      let _ = writeln!(text, "# TYPE millama_{}_total counter", name);
+      let _ = writeln!(text, "millama_{}_total {}", name, value);
+    }
+    text.push_str("# HELP millama_pending_drafts Drafts awaiting approval.\n");
+    text.push_str("# TYPE millama_pending_drafts gauge\n");
+    let _ = writeln!(text, "millama_pending_drafts {}", pending);
+    text
+  }
+}
+
+/// Answers `/healthz` and `/metrics` on `listener` until dropped. `pending`
+/// counts the drafts awaiting approval at the time of a scrape.
+pub async fn serve<P>(listener: TcpListener, metrics: Arc<Metrics>, pending: P)
+where
+  P: Fn() -> usize + Send + Sync + 'static,
+{
+  let pending = Arc::new(pending);
+  while let Ok((stream, _)) = listener.accept().await {
+    let (metrics, pending) = (metrics.clone(), pending.clone());
+    tokio::spawn(async move {
+      if let Err(e) = respond(stream, &metrics, pending()).await {
+        debug!("Failed to answer a metrics request: {}", e);
+      }
+    });
+  }
+}
+
+async fn respond(
+  mut stream: TcpStream,
+  metrics: &Metrics,
+  pending: usize,
+) -> std::io::Result<()> {
+  // Only the request line matters, and it fits in the first read
+  let mut request = vec![0; 4096];
+  let read = stream.read(&mut request).await?;
+  let request = String::from_utf8_lossy(&request[..read]);
+  let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+  let (status, body) = match path {
+    "/healthz" if metrics.healthy(unix_now()) => ("200 OK", "ok\n"),
+    "/healthz" => ("503 Service Unavailable", "stopped\n"),
+    "/metrics" => ("200 OK", &*metrics.render(pending)),
+    _ => ("404 Not Found", "not found\n"),
+  };
+  let response = format!(
+    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body
+  );
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await
+}
+
+fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, std::collections::HashMap};
+
+  #[tokio::test]
+  async fn test_metrics_endpoint_reports_counters() {
+    let metrics = Arc::new(Metrics::default());
+    metrics.count(Counter::Generated);
+    metrics.count(Counter::Generated);
+    metrics.count(Counter::Approved);
+    metrics.count(Counter::LlmFailure);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve(listener, metrics.clone(), || 2));
+
+    let body = reqwest::get(format!("{}/metrics", url))
+      .await
+      .unwrap()
+      .text()
+      .await
+      .unwrap();
+    let samples: HashMap<_, _> = body
+      .lines()
+      .filter(|line| !line.starts_with('#'))
+      .filter_map(|line| line.split_once(' '))
+      .map(|(name, value)| (name, value.parse::<u64>().unwrap()))
+      .collect();
+    assert_eq!(samples["millama_drafts_generated_total"], 2);
+    assert_eq!(samples["millama_drafts_approved_total"], 1);
+    assert_eq!(samples["millama_drafts_rejected_total"], 0);
+    assert_eq!(samples["millama_llm_failures_total"], 1);
+    assert_eq!(samples["millama_pending_drafts"], 2);
+
+    let health = |url: String| async move {
+      reqwest::get(format!("{}/healthz", url)).await.unwrap().status()
+    };
+    assert_eq!(health(url.clone()).await, 503);
+    metrics.set_alive(true);
+    // Up only while bot updates keep coming in
+    assert_eq!(health(url.clone()).await, 503);
+    metrics.polled();
+    assert_eq!(health(url).await, 200);
+
+    let now = unix_now();
+    assert!(metrics.healthy(now + POLL_STALE_SECS));
+    assert!(!metrics.healthy(now + POLL_STALE_SECS + 2));
+  }
+}
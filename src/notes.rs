@@ -0,0 +1,113 @@
+//! Short-lived side notes about a conversation, kept in a JSON lines file.
+
+use {
+  anyhow::{Context, Result},
+  serde::{Deserialize, Serialize},
+  std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+    time::Duration,
+  },
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Note {
+  pub user_id: i64,
+  /// Unix timestamp in seconds.
+  pub at: u64,
+  pub text: String,
+}
+
+/// Appends a note about `user_id` taken at `now` (unix seconds).
+pub fn add(path: &Path, user_id: i64, text: &str, now: u64) -> Result<()> {
+  let note = Note { user_id, at: now, text: text.to_string() };
+  let mut file =
+    OpenOptions::new().create(true).append(true).open(path).with_context(
+      || format!("Failed to open notes file: {}", path.display()),
+    )?;
+  writeln!(file, "{}", json::to_string(&note)?)
+    .with_context(|| format!("Failed to write notes file: {}", path.display()))
+}
+
+/// Notes about `user_id`, oldest first, leaving out those older than `ttl`.
+pub fn recent(
+  path: &Path,
+  user_id: i64,
+  now: u64,
+  ttl: Option<Duration>,
+) -> Result<Vec<Note>> {
+  let contents = match fs::read_to_string(path) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => {
+      return Err(e).with_context(|| {
+        format!("Failed to read notes file: {}", path.display())
+      });
+    }
+  };
+
+  let notes = contents
+    .lines()
+    .filter(|line| !line.trim().is_empty())
+    .filter_map(|line| json::from_str::<Note>(line).ok())
+    .filter(|note| note.user_id == user_id)
+    .filter(|note| {
+      ttl.is_none_or(|ttl| now.saturating_sub(note.at) < ttl.as_secs())
+    })
+    .collect();
+  Ok(notes)
+}
+
+/// The prompt section listing `notes`, if there are any.
+pub fn prompt_section(notes: &[Note]) -> Option<String> {
+  if notes.is_empty() {
+    return None;
+  }
+
+  let mut section =
+    String::from("\n\nThe owner's current notes about this conversation:");
+  for note in notes {
+    section.push_str("\n- ");
+    section.push_str(&note.text);
+  }
+  Some(section)
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::testing::temp_path, std::path::PathBuf};
+
+  const HOUR: u64 = 3600;
+
+  fn notes_file(name: &str) -> PathBuf {
+    temp_path(&format!("{}.jsonl", name))
+  }
+
+  #[test]
+  fn test_added_note_is_in_prompt() {
+    let path = notes_file("notes");
+    add(&path, 1, "stressed about the move, be gentle", 10 * HOUR).unwrap();
+    add(&path, 2, "someone else", 10 * HOUR).unwrap();
+
+    let notes = recent(&path, 1, 11 * HOUR, None).unwrap();
+    assert_eq!(notes.len(), 1);
+    let section = prompt_section(&notes).unwrap();
+    assert!(section.ends_with("\n- stressed about the move, be gentle"));
+
+    assert!(prompt_section(&[]).is_none());
+    assert!(recent(&notes_file("absent"), 1, 0, None).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_notes_expire_after_ttl() {
+    let path = notes_file("expiry");
+    add(&path, 1, "old", 0).unwrap();
+    add(&path, 1, "fresh", 20 * HOUR).unwrap();
+
+    let ttl = Some(Duration::from_secs(24 * HOUR));
+    let notes = recent(&path, 1, 25 * HOUR, ttl).unwrap();
+    let texts: Vec<_> = notes.iter().map(|note| note.text.as_str()).collect();
+    assert_eq!(texts, ["fresh"]);
+  }
+}
@@ -0,0 +1,67 @@
+//! Pings an external webhook (ntfy, Pushover, ...) when a draft is waiting.
+
+use {
+  anyhow::{Result, anyhow},
+  serde::Serialize,
+  std::time::Duration,
+};
+
+/// How long a notification may take before it's dropped.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Characters of the draft included in the notification.
+const PREVIEW_CHARS: usize = 100;
+
+#[derive(Debug, Serialize)]
+pub struct DraftNotification<'a> {
+  pub user: &'a str,
+  pub preview: String,
+  pub target_id: i64,
+}
+
+impl<'a> DraftNotification<'a> {
+  pub fn new(user: &'a str, draft: &str, target_id: i64) -> Self {
+    let mut preview: String = draft.chars().take(PREVIEW_CHARS).collect();
+    if preview.len() < draft.len() {
+      preview.push('…');
+    }
+    Self { user, preview, target_id }
+  }
+}
+
+pub async fn send(
+  client: &reqwest::Client,
+  url: &str,
+  notification: &DraftNotification<'_>,
+) -> Result<()> {
+  let response =
+    client.post(url).timeout(TIMEOUT).json(notification).send().await?;
+
+  let status = response.status();
+  if !status.is_success() {
+    return Err(anyhow!("Webhook returned {}", status));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::testing::{MockServer, Response},
+  };
+
+  #[tokio::test]
+  async fn test_notification_payload() {
+    let server = MockServer::start(vec![Response::json(200, "{}")]).await;
+
+    let draft = "a".repeat(150);
+    let notification = DraftNotification::new("Alice", &draft, 42);
+    let client = reqwest::Client::new();
+    send(&client, &server.url("/notify"), &notification).await.unwrap();
+
+    let payload = server.requests()[0].json();
+    assert_eq!(payload["user"], "Alice");
+    assert_eq!(payload["target_id"], 42);
+    assert_eq!(payload["preview"], format!("{}…", "a".repeat(100)));
+  }
+}
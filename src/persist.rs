@@ -0,0 +1,51 @@
+//! State kept across restarts as JSON files.
+
+use {
+  anyhow::{Context, Result},
+  serde::{Serialize, de::DeserializeOwned},
+  std::{fs, io::ErrorKind, path::Path},
+};
+
+/// Overwrites `path` with `value`, going through a temporary file so a crash
+/// midway leaves the old contents. `what` names the state in errors.
+pub fn save<T: Serialize>(path: &Path, what: &str, value: &T) -> Result<()> {
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, json::to_string(value)?)
+    .and_then(|()| fs::rename(&tmp, path))
+    .with_context(|| format!("Failed to write {}: {}", what, path.display()))
+}
+
+/// The value last saved to `path`, the default if nothing was saved yet.
+pub fn load<T: DeserializeOwned + Default>(
+  path: &Path,
+  what: &str,
+) -> Result<T> {
+  match fs::read_to_string(path) {
+    Ok(contents) => json::from_str(&contents)
+      .with_context(|| format!("Failed to parse {}: {}", what, path.display())),
+    Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+    Err(e) => Err(e)
+      .with_context(|| format!("Failed to read {}: {}", what, path.display())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::testing::temp_path, std::collections::HashMap};
+
+  #[test]
+  fn test_saved_state_loads_back() {
+    let path = temp_path("persist.json");
+    let loaded: HashMap<i64, bool> = load(&path, "mutes").unwrap();
+    assert!(loaded.is_empty());
+
+    let state = HashMap::from([(10, false), (20, true)]);
+    save(&path, "mutes", &state).unwrap();
+    assert_eq!(load::<HashMap<i64, bool>>(&path, "mutes").unwrap(), state);
+
+    fs::write(&path, "not json").unwrap();
+    let err = load::<HashMap<i64, bool>>(&path, "mutes").unwrap_err();
+    assert!(err.to_string().starts_with("Failed to parse mutes"), "{}", err);
+    let _ = fs::remove_file(&path);
+  }
+}
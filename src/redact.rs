@@ -0,0 +1,74 @@
+use std::{
+  fmt,
+  sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns log anonymization on or off for the whole process.
+pub fn set_enabled(enabled: bool) {
+  ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+/// A log value that is replaced by a stable alias while anonymization is on.
+///
+/// The alias is a hash of the value, so entries about the same user still
+/// correlate without revealing who it is.
+pub struct Redacted<T> {
+  kind: &'static str,
+  value: T,
+}
+
+/// Wraps a tracked user's name for logging.
+pub fn name<T: fmt::Display>(name: T) -> Redacted<T> {
+  Redacted { kind: "user", value: name }
+}
+
+/// Wraps a peer or chat id for logging.
+pub fn peer<T: fmt::Display>(id: T) -> Redacted<T> {
+  Redacted { kind: "peer", value: id }
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if enabled() {
+      write!(f, "{}-{:08x}", self.kind, alias(&self.value.to_string()))
+    } else {
+      self.value.fmt(f)
+    }
+  }
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is stable across builds.
+fn alias(value: &str) -> u32 {
+  value.bytes().fold(0x811c9dc5, |hash, byte| {
+    (hash ^ byte as u32).wrapping_mul(0x01000193)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_anonymized_log_uses_stable_alias() {
+    set_enabled(true);
+    let line = format!("Generated AI response for user {}", name("Alice"));
+    let again =
+      format!("Silence detected for {} ({})", name("Alice"), peer(123456789));
+    let other = name("Bob").to_string();
+    set_enabled(false);
+
+    assert!(!line.contains("Alice"));
+    let alias = line.rsplit(' ').next().unwrap();
+    assert!(alias.starts_with("user-"));
+    assert!(again.contains(alias));
+    assert!(!again.contains("123456789"));
+    assert_ne!(other, alias);
+    assert_eq!(name("Alice").to_string(), "Alice");
+  }
+}
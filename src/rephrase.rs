@@ -0,0 +1,49 @@
+//! Cards waiting for rephrase guidance, saved so a restart doesn't lose them.
+
+use {
+  crate::llm::ChatMessage,
+  serde::{Deserialize, Serialize},
+  std::time::Duration,
+};
+
+/// The card a target's rephrase guidance would replace, and the history it
+/// was drafted from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pending {
+  pub chat_id: i64,
+  pub message_id: i64,
+  pub history: Vec<ChatMessage>,
+  /// Unix timestamp in seconds of when the card was sent.
+  pub at: u64,
+  /// The contact's message a reply drafted again should answer.
+  #[serde(default)]
+  pub reply_to: Option<i32>,
+}
+
+impl Pending {
+  pub fn expired(&self, now: u64, timeout: Option<Duration>) -> bool {
+    timeout
+      .is_some_and(|timeout| now.saturating_sub(self.at) > timeout.as_secs())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pending_expires_after_the_timeout() {
+    let pending = Pending {
+      chat_id: 1,
+      message_id: 42,
+      history: Vec::new(),
+      at: 100,
+      reply_to: Some(7),
+    };
+
+    let timeout = Some(Duration::from_secs(60));
+    assert!(!pending.expired(160, timeout));
+    assert!(pending.expired(161, timeout));
+    assert!(!pending.expired(u64::MAX, None));
+  }
+}
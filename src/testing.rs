@@ -0,0 +1,155 @@
+//! A minimal HTTP server for exercising the API clients in tests.
+
+mod temp;
+
+pub use temp::temp_path;
+
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use tokio::{
+  io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+  net::{TcpListener, TcpStream},
+};
+
+#[derive(Debug, Clone)]
+pub struct Request {
+  /// The path and query the request was sent to.
+  pub target: String,
+  pub headers: Vec<(String, String)>,
+  pub body: String,
+}
+
+impl Request {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  pub fn json(&self) -> json::Value {
+    json::from_str(&self.body).expect("request body is not JSON")
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+  status: u16,
+  content_type: &'static str,
+  body: String,
+  delay: Duration,
+}
+
+impl Response {
+  pub fn json(status: u16, body: impl Into<String>) -> Self {
+    Self {
+      status,
+      content_type: "application/json",
+      body: body.into(),
+      delay: Duration::ZERO,
+    }
+  }
+
+  /// Holds the response back for `delay` after the request arrives.
+  pub fn delayed(mut self, delay: Duration) -> Self {
+    self.delay = delay;
+    self
+  }
+}
+
+struct State {
+  responses: Vec<Response>,
+  requests: Vec<Request>,
+}
+
+/// Serves the given responses in order, one per request, repeating the last
+/// one once they run out. Every request is recorded for later assertions.
+pub struct MockServer {
+  addr: String,
+  state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+  pub async fn start(responses: Vec<Response>) -> Self {
+    assert!(!responses.is_empty(), "mock server needs a response");
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let state = Arc::new(Mutex::new(State { responses, requests: Vec::new() }));
+
+    let server_state = state.clone();
+    tokio::spawn(async move {
+      while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(serve(stream, server_state.clone()));
+      }
+    });
+
+    Self { addr, state }
+  }
+
+  pub fn url(&self, path: &str) -> String {
+    format!("http://{}{}", self.addr, path)
+  }
+
+  pub fn requests(&self) -> Vec<Request> {
+    self.state.lock().unwrap().requests.clone()
+  }
+}
+
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+  let Some(request) = read_request(&mut stream).await else {
+    return;
+  };
+
+  let response = {
+    let mut state = state.lock().unwrap();
+    let idx = state.requests.len().min(state.responses.len() - 1);
+    state.requests.push(request);
+    state.responses[idx].clone()
+  };
+
+  tokio::time::sleep(response.delay).await;
+
+  let head = format!(
+    "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+     Connection: close\r\n\r\n",
+    response.status,
+    response.content_type,
+    response.body.len()
+  );
+  let _ = stream.write_all(head.as_bytes()).await;
+  let _ = stream.write_all(response.body.as_bytes()).await;
+  let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+  let mut reader = BufReader::new(stream);
+
+  let mut line = String::new();
+  reader.read_line(&mut line).await.ok()?;
+  let target = line.split_whitespace().nth(1)?.to_string();
+
+  let mut headers = Vec::new();
+  loop {
+    line.clear();
+    reader.read_line(&mut line).await.ok()?;
+    let Some((key, value)) = line.trim_end().split_once(':') else {
+      break;
+    };
+    headers.push((key.trim().to_string(), value.trim().to_string()));
+  }
+
+  let length = headers
+    .iter()
+    .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+    .and_then(|(_, value)| value.parse().ok())
+    .unwrap_or(0);
+  let mut body = vec![0; length];
+  reader.read_exact(&mut body).await.ok()?;
+
+  Some(Request { target, headers, body: String::from_utf8(body).ok()? })
+}
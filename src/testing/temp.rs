@@ -0,0 +1,16 @@
+//! Scratch files for tests, shared by the library, the binary and the
+//! integration tests.
+
+use std::path::PathBuf;
+
+/// A path called `name` in the system temp directory, unique to this test
+/// process, with whatever an earlier run left there removed.
+pub fn temp_path(name: &str) -> PathBuf {
+  let path = std::env::temp_dir().join(format!(
+    "millama-{}-{}",
+    std::process::id(),
+    name
+  ));
+  let _ = std::fs::remove_file(&path);
+  path
+}
@@ -0,0 +1,502 @@
+use {
+  crate::llm::ChatMessage, grammers_tl_types as tl, std::collections::HashSet,
+  unicode_segmentation::UnicodeSegmentation, whatlang::Lang,
+};
+
+/// Telegram's limit for a single text message, counted in UTF-16 code units.
+pub const MESSAGE_LIMIT: usize = 4096;
+
+/// Length of `text` the way Telegram counts it (UTF-16 code units).
+pub fn utf16_len(text: &str) -> usize {
+  text.encode_utf16().count()
+}
+
+/// Splits `text` into chunks of at most `limit` UTF-16 code units.
+///
+/// Chunks are only ever cut on grapheme cluster boundaries, so multi-byte
+/// characters and ZWJ emoji sequences are never broken apart. Cuts go
+/// between paragraphs if possible, else between sentences, else between
+/// words; a word longer than `limit` is cut hard.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+  let mut chunks = Vec::new();
+  let mut rest = text;
+
+  while utf16_len(rest) > limit {
+    let (head, tail) = rest.split_at(split_point(rest, limit));
+    let head = head.trim_end();
+    if !head.is_empty() {
+      chunks.push(head.to_string());
+    }
+    rest = tail.trim_start();
+  }
+
+  if !rest.is_empty() {
+    chunks.push(rest.to_string());
+  }
+
+  chunks
+}
+
+/// The paragraphs of `text`, as separated by blank lines.
+pub fn split_paragraphs(text: &str) -> Vec<String> {
+  normalize_whitespace(text)
+    .split("\n\n")
+    .filter(|p| !p.is_empty())
+    .map(str::to_string)
+    .collect()
+}
+
+/// Byte offset at which to cut `text` so the head fits into `limit`.
+fn split_point(text: &str, limit: usize) -> usize {
+  let mut units = 0;
+  let mut hard = 0;
+  let (mut paragraph, mut sentence, mut soft) = (None, None, None);
+  let mut previous = "";
+
+  for (idx, grapheme) in text.grapheme_indices(true) {
+    if idx > 0 && grapheme.chars().all(char::is_whitespace) {
+      if grapheme.contains('\n') && previous.contains('\n') {
+        paragraph = Some(idx);
+      } else if previous.ends_with(['.', '!', '?', '…']) {
+        sentence = Some(idx);
+      }
+      soft = Some(idx);
+    }
+    previous = grapheme;
+    units += utf16_len(grapheme);
+    if units > limit {
+      break;
+    }
+    hard = idx + grapheme.len();
+  }
+
+  if hard == 0 {
+    // A single grapheme wider than the limit: emit it whole to make progress
+    return text.graphemes(true).next().map_or(text.len(), str::len);
+  }
+
+  paragraph.or(sentence).or(soft).unwrap_or(hard)
+}
+
+/// Cleans model output for sending: line endings become `\n` and control
+/// characters other than newlines and tabs are dropped.
+///
+/// Only the C0/C1 control range is touched, so emoji (including ZWJ
+/// sequences and variation selectors) pass through unchanged.
+pub fn sanitize(text: &str) -> String {
+  text
+    .replace("\r\n", "\n")
+    .replace('\r', "\n")
+    .chars()
+    .filter(|&c| !c.is_control() || c == '\n' || c == '\t')
+    .collect()
+}
+
+/// Tidies blank lines and trailing spaces: runs of blank lines become a single
+/// one, blank lines at either end are dropped and each line is trimmed on the
+/// right.
+pub fn normalize_whitespace(text: &str) -> String {
+  let mut normalized = String::with_capacity(text.len());
+  let mut blank = false;
+  for line in text.lines().map(str::trim_end) {
+    if line.is_empty() {
+      blank = true;
+      continue;
+    }
+    if !normalized.is_empty() {
+      normalized.push_str(if blank { "\n\n" } else { "\n" });
+    }
+    normalized.push_str(line);
+    blank = false;
+  }
+  normalized
+}
+
+/// Whether `reply` is clearly not written in `wanted`.
+///
+/// A script `wanted` isn't written in always counts as a mismatch. Within its
+/// script the detection has to be reliable, as short messages are easily
+/// taken for a related language.
+pub fn language_mismatch(wanted: Lang, reply: &str) -> bool {
+  let Some(got) = whatlang::detect(reply) else {
+    return false;
+  };
+  if !got.script().langs().contains(&wanted) {
+    return true;
+  }
+  got.is_reliable() && got.lang() != wanted
+}
+
+/// The language most of the contact's messages in `msgs` are written in.
+///
+/// They're told apart all together, so a stray "ok" doesn't outweigh a
+/// paragraph, and only a reliable detection counts: short messages are easily
+/// taken for a related language. Own messages are ignored.
+pub fn detect_language(msgs: &[ChatMessage]) -> Option<Lang> {
+  let contact: Vec<_> = msgs
+    .iter()
+    .filter(|msg| msg.role == "user")
+    .map(|msg| msg.content.as_str())
+    .collect();
+  whatlang::detect(&contact.join("\n"))
+    .filter(|info| info.is_reliable())
+    .map(|info| info.lang())
+}
+
+/// Parses a list of suggested replies, given either as a JSON array of
+/// strings or as numbered lines (`1.` or `1)`).
+///
+/// Returns `None` unless at least two suggestions are found.
+pub fn parse_suggestions(text: &str) -> Option<Vec<String>> {
+  let text = text.trim();
+  let options = match json::from_str::<Vec<String>>(text) {
+    Ok(options) => options,
+    Err(_) => text
+      .lines()
+      .filter_map(|line| {
+        let line = line.trim_start();
+        let rest = line.trim_start_matches(|c: char| c.is_ascii_digit());
+        if rest.len() == line.len() {
+          return None;
+        }
+        rest.strip_prefix(['.', ')'])
+      })
+      .map(str::to_string)
+      .collect(),
+  };
+
+  let options: Vec<_> = options
+    .into_iter()
+    .map(|option| option.trim().to_string())
+    .filter(|option| !option.is_empty())
+    .collect();
+  (options.len() >= 2).then_some(options)
+}
+
+/// Whether `reply` is mostly lifted from `prompt`, i.e. the model echoed its
+/// instructions instead of answering.
+///
+/// Compares runs of four consecutive words after lowercasing and dropping
+/// punctuation, so small edits don't hide the overlap.
+pub fn echoes_prompt(reply: &str, prompt: &str) -> bool {
+  const RUN: usize = 4;
+
+  let reply = normalized_words(reply);
+  if reply.len() < RUN {
+    return false;
+  }
+  let prompt = normalized_words(prompt);
+  let prompt_runs: HashSet<_> = prompt.windows(RUN).collect();
+
+  let runs = reply.windows(RUN).count();
+  let echoed =
+    reply.windows(RUN).filter(|run| prompt_runs.contains(run)).count();
+  echoed * 2 >= runs
+}
+
+fn normalized_words(text: &str) -> Vec<String> {
+  text
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(str::to_lowercase)
+    .collect()
+}
+
+/// Splits Bot API (legacy) Markdown into plain text and MTProto entities, so
+/// a message sent from the userbot looks like the card previewing it.
+///
+/// Handles `*bold*`, `_italic_`, `` `code` ``, ```` ```pre``` ```` and
+/// `[text](url)`. Mention links (`tg://user?id=`) need the user's access hash
+/// and are flattened to their text, as are unclosed markers.
+pub fn markdown_entities(
+  text: &str,
+) -> (String, Vec<tl::enums::MessageEntity>) {
+  let mut plain = String::new();
+  let mut entities = Vec::new();
+  let mut rest = text;
+
+  while let Some(c) = rest.chars().next() {
+    let offset = utf16_len(&plain) as i32;
+    let after = &rest[c.len_utf8()..];
+
+    if c == '\\'
+      && let Some(escaped) =
+        after.chars().next().filter(|c| "_*`[".contains(*c))
+    {
+      plain.push(escaped);
+      rest = &after[escaped.len_utf8()..];
+      continue;
+    }
+
+    if let Some(body) = rest.strip_prefix("```")
+      && let Some(end) = body.find("```")
+    {
+      let code = body[..end].trim_matches('\n');
+      plain.push_str(code);
+      entities.push(
+        tl::types::MessageEntityPre {
+          offset,
+          length: utf16_len(code) as i32,
+          language: String::new(),
+        }
+        .into(),
+      );
+      rest = &body[end + 3..];
+      continue;
+    }
+
+    if c == '['
+      && let Some((label, tail)) = after.split_once("](")
+      && let Some((url, tail)) = tail.split_once(')')
+      && !label.contains(']')
+    {
+      plain.push_str(label);
+      if !url.starts_with("tg://user") {
+        let length = utf16_len(label) as i32;
+        entities.push(
+          tl::types::MessageEntityTextUrl {
+            offset,
+            length,
+            url: url.to_string(),
+          }
+          .into(),
+        );
+      }
+      rest = tail;
+      continue;
+    }
+
+    if "*_`".contains(c)
+      && let Some(end) = after.find(c).filter(|end| *end > 0)
+    {
+      let inner = &after[..end];
+      let length = utf16_len(inner) as i32;
+      plain.push_str(inner);
+      entities.push(match c {
+        '*' => tl::types::MessageEntityBold { offset, length }.into(),
+        '_' => tl::types::MessageEntityItalic { offset, length }.into(),
+        _ => tl::types::MessageEntityCode { offset, length }.into(),
+      });
+      rest = &after[end + 1..];
+      continue;
+    }
+
+    plain.push(c);
+    rest = after;
+  }
+
+  (plain, entities)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FAMILY: &str = "👨‍👩‍👧‍👦";
+
+  fn assert_clean(chunks: &[String], limit: usize) {
+    for chunk in chunks {
+      assert!(utf16_len(chunk) <= limit, "chunk too long: {chunk:?}");
+    }
+  }
+
+  #[test]
+  fn test_short_text_is_untouched() {
+    assert_eq!(split_message("hello", 10), vec!["hello"]);
+    assert!(split_message("", 10).is_empty());
+  }
+
+  #[test]
+  fn test_split_paragraphs() {
+    let text = "Sure!\n\n\n  \nSee you at 5.\nBring snacks  \n\n";
+    assert_eq!(
+      split_paragraphs(text),
+      ["Sure!", "See you at 5.\nBring snacks"]
+    );
+    assert!(split_paragraphs("\n\n").is_empty());
+  }
+
+  #[test]
+  fn test_split_prefers_whitespace() {
+    let chunks = split_message("hello brave new world", 11);
+    assert_eq!(chunks, vec!["hello brave", "new world"]);
+  }
+
+  #[test]
+  fn test_split_at_the_limit() {
+    let text = "a".repeat(MESSAGE_LIMIT - 4) + " end";
+    assert_eq!(split_message(&text, MESSAGE_LIMIT), vec![text.clone()]);
+
+    let longer = text.clone() + "s";
+    let chunks = split_message(&longer, MESSAGE_LIMIT);
+    assert_eq!(chunks, vec!["a".repeat(MESSAGE_LIMIT - 4), "ends".into()]);
+  }
+
+  #[test]
+  fn test_split_prefers_paragraphs_then_sentences() {
+    let first = "word ".repeat(700).trim_end().to_string() + ".";
+    let rest = format!("Next one. {}", "more ".repeat(200).trim_end());
+    let text = format!("{first}\n\n{rest}");
+    let chunks = split_message(&text, MESSAGE_LIMIT);
+    assert_eq!(chunks, vec![first.clone(), rest]);
+
+    let text = format!("{first} Next one. And more words here");
+    let chunks = split_message(&text, utf16_len(&first) + 12);
+    assert_eq!(
+      chunks,
+      vec![format!("{first} Next one."), "And more words here".into()]
+    );
+  }
+
+  #[test]
+  fn test_split_multibyte_at_boundary() {
+    // Cyrillic is 2 bytes in UTF-8 but a single UTF-16 unit
+    let text = "приветмир";
+    let chunks = split_message(text, 4);
+    assert_clean(&chunks, 4);
+    assert_eq!(chunks.concat(), text);
+  }
+
+  #[test]
+  fn test_split_surrogate_pair_at_boundary() {
+    // Each emoji takes 2 UTF-16 units, so a limit of 3 must not halve one
+    let text = "a😀😀";
+    let chunks = split_message(text, 3);
+    assert_eq!(chunks, vec!["a😀", "😀"]);
+  }
+
+  #[test]
+  fn test_split_keeps_zwj_family_intact() {
+    let text = format!("abc{FAMILY}def");
+    let limit = 3 + utf16_len(FAMILY) - 1;
+    let chunks = split_message(&text, limit);
+    assert_clean(&chunks, limit);
+    assert_eq!(chunks.concat(), text);
+    assert!(chunks.iter().any(|chunk| chunk.contains(FAMILY)));
+  }
+
+  #[test]
+  fn test_sanitize_strips_control_characters() {
+    let reply =
+      format!("Hi\u{0}\u{7} there!\r\n\r\nSee {FAMILY}\u{1b}[0m ❤️\u{85}");
+    assert_eq!(sanitize(&reply), format!("Hi there!\n\nSee {FAMILY}[0m ❤️"));
+    assert_eq!(sanitize("tab\tkept"), "tab\tkept");
+  }
+
+  #[test]
+  fn test_normalize_whitespace() {
+    let reply = "\n \nHey!   \nHow are you?\t\n\n\n\n  Indented\n\nBye\n\n";
+    assert_eq!(
+      normalize_whitespace(reply),
+      "Hey!\nHow are you?\n\n  Indented\n\nBye"
+    );
+  }
+
+  #[test]
+  fn test_language_mismatch() {
+    assert!(language_mismatch(Lang::Rus, "Sure, I'll be there at seven!"));
+    assert!(!language_mismatch(Lang::Rus, "Да, буду в семь!"));
+    // Too short to tell from Bulgarian, but in the right script
+    assert!(!language_mismatch(Lang::Rus, "Да, конечно, увидимся"));
+    assert!(!language_mismatch(Lang::Eng, "Sure, see you"));
+  }
+
+  #[test]
+  fn test_detect_language_goes_by_the_contact() {
+    let msg = |role: &str, content: &str| ChatMessage {
+      role: role.into(),
+      content: content.into(),
+    };
+    let history = [
+      msg("user", "Привет! Как у тебя дела, что нового на работе?"),
+      msg("assistant", "Hi! All good, busy week at work though."),
+      msg("assistant", "How about you? Anything exciting going on lately?"),
+      msg("user", "ok"),
+      msg("user", "Да всё по-старому, вечером хочу сходить в кино."),
+    ];
+    assert_eq!(detect_language(&history), Some(Lang::Rus));
+
+    let mixed = [
+      msg("user", "Hey, are we still meeting tomorrow at the station?"),
+      msg("user", "Да"),
+      msg("user", "I'll bring the tickets and some snacks for the road."),
+    ];
+    assert_eq!(detect_language(&mixed), Some(Lang::Eng));
+    assert_eq!(detect_language(&history[1..3]), None);
+    assert_eq!(detect_language(&[]), None);
+
+    // Short messages are easily taken for a related language, so none is named
+    let short = [msg("user", "Да, конечно, увидимся"), msg("user", "ок")];
+    assert_eq!(detect_language(&short), None);
+    // A Russian chat that ends in a stray English "ok" is still Russian
+    let ends_in_ok =
+      [history[0].clone(), history[4].clone(), msg("user", "ok")];
+    assert_eq!(detect_language(&ends_in_ok), Some(Lang::Rus));
+  }
+
+  #[test]
+  fn test_parse_suggestions() {
+    let json = r#"["Sure!", "Not today", "Let me check"]"#;
+    assert_eq!(
+      parse_suggestions(json).unwrap(),
+      ["Sure!", "Not today", "Let me check"]
+    );
+
+    let numbered = "Here you go:\n1. Sure!\n2) Not today\n3. Let me check";
+    assert_eq!(
+      parse_suggestions(numbered).unwrap(),
+      parse_suggestions(json).unwrap()
+    );
+
+    assert!(parse_suggestions("Just one plain reply").is_none());
+  }
+
+  #[test]
+  fn test_reply_echoing_prompt_is_detected() {
+    let prompt = "You are drafting replies for Alice. Keep them short, \
+                  friendly and never mention that you are an AI.";
+
+    let echo = "As an AI drafting replies for Alice: keep them short, friendly \
+                and never mention that you are an AI!";
+    assert!(echoes_prompt(echo, prompt));
+
+    assert!(!echoes_prompt("Sure, see you at noon!", prompt));
+    assert!(!echoes_prompt(
+      "Keep them coming, I love short friendly notes from you",
+      prompt
+    ));
+  }
+
+  #[test]
+  fn test_grapheme_wider_than_limit_is_emitted_whole() {
+    let chunks = split_message(FAMILY, 4);
+    assert_eq!(chunks, vec![FAMILY]);
+  }
+
+  #[test]
+  fn test_markdown_becomes_entities() {
+    use tl::enums::MessageEntity;
+
+    let (plain, entities) =
+      markdown_entities("👋 *Hi* there, _see_ [docs](https://x.y) \\*5\\*");
+    assert_eq!(plain, "👋 Hi there, see docs *5*");
+    match entities.as_slice() {
+      [
+        MessageEntity::Bold(bold),
+        MessageEntity::Italic(italic),
+        MessageEntity::TextUrl(link),
+      ] => {
+        // The emoji is two UTF-16 code units
+        assert_eq!((bold.offset, bold.length), (3, 2));
+        assert_eq!((italic.offset, italic.length), (13, 3));
+        assert_eq!(link.url, "https://x.y");
+      }
+      other => panic!("unexpected entities: {:?}", other),
+    }
+
+    let (plain, entities) =
+      markdown_entities("[Bob](tg://user?id=1) 2*3 ```\nlet x;\n```");
+    assert_eq!(plain, "Bob 2*3 let x;");
+    assert!(matches!(entities.as_slice(), [MessageEntity::Pre(_)]));
+  }
+}
@@ -0,0 +1,65 @@
+use std::{fs, path::PathBuf, process::Command};
+
+#[path = "../src/testing/temp.rs"]
+mod temp;
+
+use temp::temp_path;
+
+const VALID: &str = r#"
+[telegram]
+api_id = 1
+bot_token = "token"
+
+[ai]
+api_url = "http://localhost"
+models = ["model-a", "model-b"]
+temperature = 0.5
+
+[settings]
+
+[[users]]
+id = 12345
+name = "Alice"
+system_prompt = "Be nice."
+"#;
+
+fn write_config(name: &str, contents: &str) -> PathBuf {
+  let path = temp_path(&format!("{}.toml", name));
+  fs::write(&path, contents).unwrap();
+  path
+}
+
+fn check(path: &PathBuf) -> std::process::Output {
+  Command::new(env!("CARGO_BIN_EXE_millama"))
+    .arg("check")
+    .arg("--config")
+    .arg(path)
+    .output()
+    .unwrap()
+}
+
+#[test]
+fn test_check_lists_tracked_users() {
+  let path = write_config("check-valid", VALID);
+  let output = check(&path);
+  let _ = fs::remove_file(&path);
+
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(output.status.success(), "{}", stdout);
+  assert!(stdout.contains("Models: model-a, model-b"), "{}", stdout);
+  assert!(stdout.contains("Temperature: 0.5"), "{}", stdout);
+  let alice = stdout.lines().find(|line| line.starts_with("12345"));
+  let alice = alice.unwrap_or_else(|| panic!("{}", stdout));
+  assert!(alice.contains("Alice") && alice.ends_with("8 chars"), "{}", alice);
+}
+
+#[test]
+fn test_check_fails_on_invalid_config() {
+  let path = write_config("check-invalid", &VALID.replace("0.5", "3.0"));
+  let output = check(&path);
+  let _ = fs::remove_file(&path);
+
+  assert!(!output.status.success());
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert!(stderr.contains("Invalid configuration"), "{}", stderr);
+}